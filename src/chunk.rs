@@ -1,31 +1,143 @@
+use crate::scanner::Span;
 use crate::value::Value;
 use num_enum::TryFromPrimitive;
-use std::ops::Index;
+use std::ops::{Index, IndexMut};
+use std::sync::Arc;
 use std::vec::Vec;
 
 #[derive(Copy, Clone, TryFromPrimitive)]
 #[repr(u8)]
 pub enum OpCode {
     Constant,
+    ConstantLong,
     Nil,
     True,
     False,
+    Pop,
+    GetLocal,
+    SetLocal,
+    GetGlobal,
+    GetGlobalLong,
+    DefineGlobal,
+    DefineGlobalLong,
+    SetGlobal,
+    SetGlobalLong,
+    GetUpvalue,
+    SetUpvalue,
+    GetProperty,
+    GetPropertyLong,
+    SetProperty,
+    SetPropertyLong,
+    GetSuper,
+    GetSuperLong,
+    BuildList,
+    BuildMap,
+    IndexGet,
+    IndexSet,
+    Slice,
     Equal,
     Greater,
     Less,
+    In,
     Add,
     Subtract,
     Multiply,
     Divide,
+    // Division that truncates towards zero, like `%`
+    IntDivide,
+    Modulo,
+    Power,
+    BitAnd,
+    BitOr,
+    BitXor,
+    ShiftLeft,
+    ShiftRight,
     Not,
     Negate,
+    BitNot,
+    // Replaces the value on top of the stack with the string `print` would show for it
+    Stringify,
+    Print,
+    Jump,
+    JumpIfFalse,
+    Loop,
+    Call,
+    Invoke,
+    InvokeLong,
+    SuperInvoke,
+    SuperInvokeLong,
+    Closure,
+    ClosureLong,
+    CloseUpvalue,
     Return,
+    Class,
+    ClassLong,
+    Inherit,
+    Method,
+    MethodLong,
+}
+
+impl OpCode {
+    // The variant of an instruction that takes a three-byte constant index rather than one byte
+    pub fn long(self) -> OpCode {
+        match self {
+            OpCode::Constant => OpCode::ConstantLong,
+            OpCode::GetGlobal => OpCode::GetGlobalLong,
+            OpCode::DefineGlobal => OpCode::DefineGlobalLong,
+            OpCode::SetGlobal => OpCode::SetGlobalLong,
+            OpCode::GetProperty => OpCode::GetPropertyLong,
+            OpCode::SetProperty => OpCode::SetPropertyLong,
+            OpCode::GetSuper => OpCode::GetSuperLong,
+            OpCode::Invoke => OpCode::InvokeLong,
+            OpCode::SuperInvoke => OpCode::SuperInvokeLong,
+            OpCode::Closure => OpCode::ClosureLong,
+            OpCode::Class => OpCode::ClassLong,
+            OpCode::Method => OpCode::MethodLong,
+            _ => unreachable!("no long variant"),
+        }
+    }
+
+    pub fn is_long(self) -> bool {
+        matches!(
+            self,
+            OpCode::ConstantLong
+                | OpCode::GetGlobalLong
+                | OpCode::DefineGlobalLong
+                | OpCode::SetGlobalLong
+                | OpCode::GetPropertyLong
+                | OpCode::SetPropertyLong
+                | OpCode::GetSuperLong
+                | OpCode::InvokeLong
+                | OpCode::SuperInvokeLong
+                | OpCode::ClosureLong
+                | OpCode::ClassLong
+                | OpCode::MethodLong
+        )
+    }
+}
+
+// The first byte of a run of code generated from the same source line
+struct LineStart {
+    offset: usize,
+    line: usize,
+}
+
+// The first byte of a run of code generated from the same source span. Only the span's position
+// in the source is kept; its column is worked out from the source text when it's needed.
+struct SpanStart {
+    offset: u32,
+    start: u32,
+    len: u32,
 }
 
 pub struct Chunk {
     code: Vec<u8>,
-    pub lines: Vec<usize>,
+    // Both sorted by offset, with one entry per run rather than per byte
+    lines: Vec<LineStart>,
+    spans: Vec<SpanStart>,
     pub constants: Vec<Value>,
+    // The source text the spans point into
+    pub source: Arc<str>,
 }
 
 impl Chunk {
@@ -33,13 +145,65 @@ impl Chunk {
         Chunk {
             code: Vec::new(),
             lines: Vec::new(),
+            spans: Vec::new(),
             constants: Vec::new(),
+            source: Arc::from(""),
         }
     }
 
-    pub fn write_chunk(&mut self, byte: u8, line: usize) {
+    pub fn write_chunk(&mut self, byte: u8, span: Span) {
         self.code.push(byte);
-        self.lines.push(line);
+        let offset = self.code.len() - 1;
+
+        if self
+            .lines
+            .last()
+            .is_none_or(|start| start.line != span.line)
+        {
+            self.lines.push(LineStart {
+                offset,
+                line: span.line,
+            });
+        }
+
+        let (start, len) = (span.start as u32, span.len as u32);
+        if self
+            .spans
+            .last()
+            .is_none_or(|run| (run.start, run.len) != (start, len))
+        {
+            self.spans.push(SpanStart {
+                offset: offset as u32,
+                start,
+                len,
+            });
+        }
+    }
+
+    pub fn get_span(&self, offset: usize) -> Span {
+        // Find the last run starting at or before `offset`
+        let run = self
+            .spans
+            .partition_point(|run| run.offset as usize <= offset);
+        let (start, len) = (
+            self.spans[run - 1].start as usize,
+            self.spans[run - 1].len as usize,
+        );
+
+        let before = &self.source[..start];
+        let line_start = before.rfind('\n').map_or(0, |newline| newline + 1);
+        Span {
+            start,
+            len,
+            line: self.get_line(offset),
+            column: before[line_start..].chars().count() + 1,
+        }
+    }
+
+    pub fn get_line(&self, offset: usize) -> usize {
+        // Find the last run starting at or before `offset`
+        let run = self.lines.partition_point(|start| start.offset <= offset);
+        self.lines[run - 1].line
     }
 
     pub fn len(&self) -> usize {
@@ -50,12 +214,6 @@ impl Chunk {
         self.constants.push(value);
         self.constants.len() - 1
     }
-
-    pub fn clear(&mut self) {
-        self.code.clear();
-        self.lines.clear();
-        self.constants.clear();
-    }
 }
 
 impl Index<usize> for Chunk {
@@ -65,3 +223,9 @@ impl Index<usize> for Chunk {
         &self.code[index]
     }
 }
+
+impl IndexMut<usize> for Chunk {
+    fn index_mut(&mut self, index: usize) -> &mut Self::Output {
+        &mut self.code[index]
+    }
+}
@@ -1,17 +1,71 @@
 use crate::chunk::{Chunk, OpCode};
 use crate::debug::disassemble_chunk;
-use crate::scanner::{Scanner, Token, TokenType};
+use crate::diagnostic::Diagnostic;
+use crate::object::{FunctionObj, Obj, StringObj};
+use crate::scanner::{unescape, Scanner, Span, Token, TokenType};
 use crate::value::Value;
+use crate::vm::VM;
 use num_enum::TryFromPrimitive;
+use std::collections::HashMap;
+use std::mem;
+use std::num::IntErrorKind;
+use std::sync::Arc;
+
+const UINT8_COUNT: usize = u8::MAX as usize + 1;
+// Largest constant index that fits in the 24-bit operand of `ConstantLong`
+const CONSTANT_LONG_MAX: usize = (1 << 24) - 1;
+
+struct Local<'a> {
+    name: Token<'a>,
+    // `None` until the variable's initializer has been compiled
+    depth: Option<usize>,
+    is_captured: bool,
+}
+
+#[derive(Copy, Clone)]
+struct Upvalue {
+    index: u8,
+    // Whether the upvalue captures a local of the enclosing function or one of its upvalues
+    is_local: bool,
+}
+
+#[derive(Copy, Clone, PartialEq)]
+enum FunctionType {
+    Function,
+    Initializer,
+    Method,
+    Script,
+}
+
+struct Compiler<'a> {
+    enclosing: Option<Box<Compiler<'a>>>,
+    function: *mut FunctionObj,
+    function_type: FunctionType,
+    locals: Vec<Local<'a>>,
+    upvalues: Vec<Upvalue>,
+    scope_depth: usize,
+    // The constant holding each name used in the function so far
+    names: HashMap<*mut StringObj, usize>,
+}
+
+struct ClassCompiler {
+    enclosing: Option<Box<ClassCompiler>>,
+    has_superclass: bool,
+}
 
 pub struct Parser<'a> {
+    // Shared with every chunk compiled from it, so errors can be shown in context later
+    source: Arc<str>,
     scanner: Scanner<'a>,
     current: Token<'a>,
     previous: Token<'a>,
-    had_error: bool,
+    // Every error reported so far; panic mode keeps cascading errors out of it
+    diagnostics: Vec<Diagnostic>,
     panic_mode: bool,
-    chunk: &'a mut Chunk,
-    all_strings: Vec<String>,
+    compiler: Compiler<'a>,
+    // The innermost class being compiled, if any
+    class_compiler: Option<Box<ClassCompiler>>,
+    vm: &'a mut VM,
 }
 
 #[derive(Copy, Clone, PartialEq, PartialOrd, TryFromPrimitive)]
@@ -22,15 +76,20 @@ enum Precedence {
     Or,         // or
     And,        // and
     Equality,   // == !=
-    Comparison, // < > >= <=
+    Comparison, // < > >= <= in
+    BitOr,      // |
+    BitXor,     // ^
+    BitAnd,     // &
+    Shift,      // << >>
     Term,       // + -
-    Factor,     // * /
-    Unary,      // ! -
-    Call,       // . ()
+    Factor,     // * / %
+    Unary,      // ! - ~
+    Exponent,   // **
+    Call,       // . () []
     Primary,
 }
 
-type ParseFn = fn(&mut Parser);
+type ParseFn = fn(&mut Parser, bool);
 
 struct ParseRule {
     prefix: Option<ParseFn>,
@@ -38,12 +97,14 @@ struct ParseRule {
     precedence: Precedence,
 }
 
-const RULES: [ParseRule; 40] = [
+// Closures are required so that the parse functions coerce to `ParseFn`
+#[allow(clippy::redundant_closure)]
+const RULES: [ParseRule; 54] = [
     // [0] LeftParen
     ParseRule {
-        prefix: Some(|p| Parser::grouping(p)),
-        infix: None,
-        precedence: Precedence::None,
+        prefix: Some(|p, _| Parser::grouping(p)),
+        infix: Some(|p, _| Parser::call(p)),
+        precedence: Precedence::Call,
     },
     // [1] RightParen
     ParseRule {
@@ -53,7 +114,7 @@ const RULES: [ParseRule; 40] = [
     },
     // [2] LeftBrace
     ParseRule {
-        prefix: None,
+        prefix: Some(|p, _| Parser::map(p)),
         infix: None,
         precedence: Precedence::None,
     },
@@ -63,217 +124,301 @@ const RULES: [ParseRule; 40] = [
         infix: None,
         precedence: Precedence::None,
     },
-    // [4] Comma
+    // [4] LeftBracket
+    ParseRule {
+        prefix: Some(|p, _| Parser::list(p)),
+        infix: Some(|p, can_assign| Parser::subscript(p, can_assign)),
+        precedence: Precedence::Call,
+    },
+    // [5] RightBracket
     ParseRule {
         prefix: None,
         infix: None,
         precedence: Precedence::None,
     },
-    // [5] Dot
+    // [6] Comma
     ParseRule {
         prefix: None,
         infix: None,
         precedence: Precedence::None,
     },
-    // [6] Minus
+    // [7] Dot
+    ParseRule {
+        prefix: None,
+        infix: Some(|p, can_assign| Parser::dot(p, can_assign)),
+        precedence: Precedence::Call,
+    },
+    // [8] Minus
     ParseRule {
-        prefix: Some(|p| Parser::unary(p)),
-        infix: Some(|p| Parser::binary(p)),
+        prefix: Some(|p, _| Parser::unary(p)),
+        infix: Some(|p, _| Parser::binary(p)),
         precedence: Precedence::Term,
     },
-    // [7] Plus
+    // [9] Plus
     ParseRule {
         prefix: None,
-        infix: Some(|p| Parser::binary(p)),
+        infix: Some(|p, _| Parser::binary(p)),
         precedence: Precedence::Term,
     },
-    // [8] Semicolon
+    // [10] Semicolon
     ParseRule {
         prefix: None,
         infix: None,
         precedence: Precedence::None,
     },
-    // [9] Slash
+    // [11] Colon
+    ParseRule {
+        prefix: None,
+        infix: None,
+        precedence: Precedence::None,
+    },
+    // [12] Slash
+    ParseRule {
+        prefix: None,
+        infix: Some(|p, _| Parser::binary(p)),
+        precedence: Precedence::Factor,
+    },
+    // [13] Star
     ParseRule {
         prefix: None,
-        infix: Some(|p| Parser::binary(p)),
+        infix: Some(|p, _| Parser::binary(p)),
         precedence: Precedence::Factor,
     },
-    // [10] Star
+    // [14] StarStar
     ParseRule {
         prefix: None,
-        infix: Some(|p| Parser::binary(p)),
+        infix: Some(|p, _| Parser::binary(p)),
+        precedence: Precedence::Exponent,
+    },
+    // [15] Percent
+    ParseRule {
+        prefix: None,
+        infix: Some(|p, _| Parser::binary(p)),
         precedence: Precedence::Factor,
     },
-    // [11] Bang
+    // [16] Ampersand
     ParseRule {
-        prefix: Some(|p| Parser::unary(p)),
+        prefix: None,
+        infix: Some(|p, _| Parser::binary(p)),
+        precedence: Precedence::BitAnd,
+    },
+    // [17] Pipe
+    ParseRule {
+        prefix: None,
+        infix: Some(|p, _| Parser::binary(p)),
+        precedence: Precedence::BitOr,
+    },
+    // [18] Caret
+    ParseRule {
+        prefix: None,
+        infix: Some(|p, _| Parser::binary(p)),
+        precedence: Precedence::BitXor,
+    },
+    // [19] Tilde
+    ParseRule {
+        prefix: Some(|p, _| Parser::unary(p)),
+        infix: None,
+        precedence: Precedence::None,
+    },
+    // [20] TildeSlash
+    ParseRule {
+        prefix: None,
+        infix: Some(|p, _| Parser::binary(p)),
+        precedence: Precedence::Factor,
+    },
+    // [21] Bang
+    ParseRule {
+        prefix: Some(|p, _| Parser::unary(p)),
         infix: None,
         precedence: Precedence::None,
     },
-    // [12] BangEqual
+    // [22] BangEqual
     ParseRule {
         prefix: None,
-        infix: Some(|p| Parser::binary(p)),
+        infix: Some(|p, _| Parser::binary(p)),
         precedence: Precedence::Equality,
     },
-    // [13] Equal
+    // [23] Equal
     ParseRule {
         prefix: None,
         infix: None,
         precedence: Precedence::None,
     },
-    // [14] EqualEqual
+    // [24] EqualEqual
     ParseRule {
         prefix: None,
-        infix: Some(|p| Parser::binary(p)),
+        infix: Some(|p, _| Parser::binary(p)),
         precedence: Precedence::Equality,
     },
-    // [15] Greater
+    // [25] Greater
     ParseRule {
         prefix: None,
-        infix: Some(|p| Parser::binary(p)),
+        infix: Some(|p, _| Parser::binary(p)),
         precedence: Precedence::Comparison,
     },
-    // [16] GreaterEqual
+    // [26] GreaterEqual
     ParseRule {
         prefix: None,
-        infix: Some(|p| Parser::binary(p)),
+        infix: Some(|p, _| Parser::binary(p)),
         precedence: Precedence::Comparison,
     },
-    // [17] Less
+    // [27] GreaterGreater
+    ParseRule {
+        prefix: None,
+        infix: Some(|p, _| Parser::binary(p)),
+        precedence: Precedence::Shift,
+    },
+    // [28] Less
     ParseRule {
         prefix: None,
-        infix: Some(|p| Parser::binary(p)),
+        infix: Some(|p, _| Parser::binary(p)),
         precedence: Precedence::Comparison,
     },
-    // [18] LessEqual
+    // [29] LessEqual
     ParseRule {
         prefix: None,
-        infix: Some(|p| Parser::binary(p)),
+        infix: Some(|p, _| Parser::binary(p)),
         precedence: Precedence::Comparison,
     },
-    // [19] Identifier
+    // [30] LessLess
     ParseRule {
         prefix: None,
+        infix: Some(|p, _| Parser::binary(p)),
+        precedence: Precedence::Shift,
+    },
+    // [31] Identifier
+    ParseRule {
+        prefix: Some(|p, can_assign| Parser::variable(p, can_assign)),
         infix: None,
         precedence: Precedence::None,
     },
-    // [20] String
+    // [32] String
     ParseRule {
-        prefix: Some(|p| Parser::string(p)),
+        prefix: Some(|p, _| Parser::string(p)),
         infix: None,
         precedence: Precedence::None,
     },
-    // [21] Number
+    // [33] Interpolation
     ParseRule {
-        prefix: Some(|p| Parser::number(p)),
+        prefix: Some(|p, _| Parser::interpolation(p)),
         infix: None,
         precedence: Precedence::None,
     },
-    // [22] And
+    // [34] Number
     ParseRule {
-        prefix: None,
+        prefix: Some(|p, _| Parser::number(p)),
         infix: None,
         precedence: Precedence::None,
     },
-    // [23] Class
+    // [35] And
+    ParseRule {
+        prefix: None,
+        infix: Some(|p, _| Parser::and_(p)),
+        precedence: Precedence::And,
+    },
+    // [36] Class
     ParseRule {
         prefix: None,
         infix: None,
         precedence: Precedence::None,
     },
-    // [24] Else
+    // [37] Else
     ParseRule {
         prefix: None,
         infix: None,
         precedence: Precedence::None,
     },
-    // [25] False
+    // [38] False
     ParseRule {
-        prefix: Some(|p| Parser::literal(p)),
+        prefix: Some(|p, _| Parser::literal(p)),
         infix: None,
         precedence: Precedence::None,
     },
-    // [26] For
+    // [39] For
     ParseRule {
         prefix: None,
         infix: None,
         precedence: Precedence::None,
     },
-    // [27] Fun
+    // [40] Fun
     ParseRule {
         prefix: None,
         infix: None,
         precedence: Precedence::None,
     },
-    // [28] If
+    // [41] If
     ParseRule {
         prefix: None,
         infix: None,
         precedence: Precedence::None,
     },
-    // [29] Nil
+    // [42] In
     ParseRule {
-        prefix: Some(|p| Parser::literal(p)),
+        prefix: None,
+        infix: Some(|p, _| Parser::binary(p)),
+        precedence: Precedence::Comparison,
+    },
+    // [43] Nil
+    ParseRule {
+        prefix: Some(|p, _| Parser::literal(p)),
         infix: None,
         precedence: Precedence::None,
     },
-    // [30] Or
+    // [44] Or
     ParseRule {
         prefix: None,
-        infix: None,
-        precedence: Precedence::None,
+        infix: Some(|p, _| Parser::or_(p)),
+        precedence: Precedence::Or,
     },
-    // [31] Print
+    // [45] Print
     ParseRule {
         prefix: None,
         infix: None,
         precedence: Precedence::None,
     },
-    // [32] Return
+    // [46] Return
     ParseRule {
         prefix: None,
         infix: None,
         precedence: Precedence::None,
     },
-    // [33] Super
+    // [47] Super
     ParseRule {
-        prefix: None,
+        prefix: Some(|p, _| Parser::super_(p)),
         infix: None,
         precedence: Precedence::None,
     },
-    // [34] This
+    // [48] This
     ParseRule {
-        prefix: None,
+        prefix: Some(|p, _| Parser::this_(p)),
         infix: None,
         precedence: Precedence::None,
     },
-    // [35] True
+    // [49] True
     ParseRule {
-        prefix: Some(|p| Parser::literal(p)),
+        prefix: Some(|p, _| Parser::literal(p)),
         infix: None,
         precedence: Precedence::None,
     },
-    // [36] Var
+    // [50] Var
     ParseRule {
         prefix: None,
         infix: None,
         precedence: Precedence::None,
     },
-    // [37] While
+    // [51] While
     ParseRule {
         prefix: None,
         infix: None,
         precedence: Precedence::None,
     },
-    // [38] Error
+    // [52] Error
     ParseRule {
         prefix: None,
         infix: None,
         precedence: Precedence::None,
     },
-    // [39] Eof
+    // [53] Eof
     ParseRule {
         prefix: None,
         infix: None,
@@ -281,34 +426,199 @@ const RULES: [ParseRule; 40] = [
     },
 ];
 
-fn get_rule<'a>(token_type: TokenType) -> &'static ParseRule {
+fn get_rule(token_type: TokenType) -> &'static ParseRule {
     &RULES[token_type as usize]
 }
 
+// Checks and converts the text of a number token. Literals with a fraction or exponent are floats,
+// and everything else is an integer. The `num` native parses strings with this too.
+pub(crate) fn parse_number(text: &str) -> Result<Value, &'static str> {
+    const INVALID: &str = "Invalid number literal.";
+    const TOO_LARGE: &str = "Number literal is too large.";
+
+    let (digits, radix) = match text.get(..2) {
+        Some("0x") => (&text[2..], 16),
+        Some("0o") => (&text[2..], 8),
+        Some("0b") => (&text[2..], 2),
+        _ => (text, 10),
+    };
+
+    // The digits must start with a digit, which also keeps out the signs `from_str_radix` accepts
+    if !digits.starts_with(|c: char| c.is_digit(radix)) {
+        return Err(INVALID);
+    }
+
+    // Separators can only go between two digits
+    let chars: Vec<char> = digits.chars().collect();
+    let separators_valid = chars.iter().enumerate().all(|(i, &c)| {
+        c != '_'
+            || (i > 0
+                && i + 1 < chars.len()
+                && chars[i - 1].is_digit(radix)
+                && chars[i + 1].is_digit(radix))
+    });
+    if !separators_valid {
+        return Err(INVALID);
+    }
+    let digits = digits.replace('_', "");
+
+    if radix == 10 && digits.contains(['.', 'e', 'E']) {
+        match digits.parse::<f64>() {
+            Ok(value) if value.is_infinite() => Err(TOO_LARGE),
+            Ok(value) => Ok(Value::Number(value)),
+            Err(_) => Err(INVALID),
+        }
+    } else {
+        match i64::from_str_radix(&digits, radix) {
+            Ok(value) => Ok(Value::Int(value)),
+            Err(error) if *error.kind() == IntErrorKind::PosOverflow => Err(TOO_LARGE),
+            Err(_) => Err(INVALID),
+        }
+    }
+}
+
+// The text of a string token between its delimiters, which are `"` or `}` at the start and `"` or
+// `${` at the end
+fn string_contents<'a>(token: Token<'a>) -> &'a str {
+    let end = match token.token_type {
+        TokenType::Interpolation => 2,
+        _ => 1,
+    };
+    &token.value[1..token.value.len() - end]
+}
+
+fn synthetic_token(text: &'static str, span: Span) -> Token<'static> {
+    Token {
+        token_type: TokenType::Identifier,
+        value: text,
+        span,
+    }
+}
+
+impl<'a> Compiler<'a> {
+    fn new(function_type: FunctionType, function: *mut FunctionObj) -> Self {
+        let mut locals = Vec::with_capacity(UINT8_COUNT);
+
+        // Slot zero holds the function being called, or the receiver in methods
+        let slot_zero = match function_type {
+            FunctionType::Method | FunctionType::Initializer => "this",
+            FunctionType::Function | FunctionType::Script => "",
+        };
+        locals.push(Local {
+            name: Token {
+                token_type: TokenType::Identifier,
+                value: slot_zero,
+                span: Span::default(),
+            },
+            depth: Some(0),
+            is_captured: false,
+        });
+
+        Compiler {
+            enclosing: None,
+            function,
+            function_type,
+            locals,
+            upvalues: Vec::new(),
+            scope_depth: 0,
+            names: HashMap::new(),
+        }
+    }
+
+    fn resolve_local(&self, name: Token) -> Result<Option<u8>, &'static str> {
+        let local = self
+            .locals
+            .iter()
+            .enumerate()
+            .rev()
+            .find(|(_, local)| local.name.value == name.value);
+
+        match local {
+            Some((_, local)) if local.depth.is_none() => {
+                Err("Can't read local variable in its own initializer.")
+            }
+            Some((slot, _)) => Ok(Some(slot as u8)),
+            None => Ok(None),
+        }
+    }
+
+    fn add_upvalue(&mut self, index: u8, is_local: bool) -> Result<u8, &'static str> {
+        let existing = self
+            .upvalues
+            .iter()
+            .position(|upvalue| upvalue.index == index && upvalue.is_local == is_local);
+        if let Some(existing) = existing {
+            return Ok(existing as u8);
+        }
+
+        if self.upvalues.len() == UINT8_COUNT {
+            return Err("Too many closure variables in function.");
+        }
+
+        self.upvalues.push(Upvalue { index, is_local });
+        Ok((self.upvalues.len() - 1) as u8)
+    }
+
+    fn resolve_upvalue(&mut self, name: Token) -> Result<Option<u8>, &'static str> {
+        let enclosing = match self.enclosing.as_mut() {
+            Some(enclosing) => enclosing,
+            None => return Ok(None),
+        };
+
+        if let Some(local) = enclosing.resolve_local(name)? {
+            enclosing.locals[local as usize].is_captured = true;
+            return self.add_upvalue(local, true).map(Some);
+        }
+
+        if let Some(upvalue) = enclosing.resolve_upvalue(name)? {
+            return self.add_upvalue(upvalue, false).map(Some);
+        }
+
+        Ok(None)
+    }
+}
+
 impl<'a> Parser<'a> {
-    pub fn new(source: &'a str, chunk: &'a mut Chunk) -> Self {
+    pub fn new(source: &'a str, vm: &'a mut VM) -> Self {
         let default_token = Token {
             token_type: TokenType::Eof,
             value: "",
-            line: 0,
+            span: Span::default(),
         };
+        let source_arc: Arc<str> = Arc::from(source);
+        let function = unsafe { vm.new_function() };
+        unsafe { (*function).chunk.source = Arc::clone(&source_arc) };
         Parser {
+            source: source_arc,
             scanner: Scanner::new(source),
             current: default_token,
             previous: default_token,
-            had_error: false,
+            diagnostics: Vec::new(),
             panic_mode: false,
-            chunk: chunk,
-            all_strings: Vec::new(),
+            compiler: Compiler::new(FunctionType::Script, function),
+            class_compiler: None,
+            vm,
         }
     }
 
-    pub fn compile(&mut self) -> bool {
+    pub fn compile(&mut self) -> Result<*mut FunctionObj, Vec<Diagnostic>> {
         self.advance();
-        self.expression();
-        self.consume(TokenType::Eof, "Expected end of expression.");
-        self.end_compiler();
-        !self.had_error
+
+        while !self.match_(TokenType::Eof) {
+            self.declaration(true);
+        }
+
+        let (function, _) = self.end_compiler();
+        if self.diagnostics.is_empty() {
+            Ok(function)
+        } else {
+            Err(mem::take(&mut self.diagnostics))
+        }
+    }
+
+    #[inline]
+    fn current_chunk(&mut self) -> &mut Chunk {
+        unsafe { &mut (*self.compiler.function).chunk }
     }
 
     fn advance(&mut self) {
@@ -333,8 +643,27 @@ impl<'a> Parser<'a> {
         }
     }
 
+    #[inline]
+    fn check(&self, token_type: TokenType) -> bool {
+        self.current.token_type == token_type
+    }
+
+    fn match_(&mut self, token_type: TokenType) -> bool {
+        if !self.check(token_type) {
+            return false;
+        }
+        self.advance();
+        true
+    }
+
     fn emit_byte(&mut self, byte: u8) {
-        self.chunk.write_chunk(byte, self.previous.line);
+        let span = self.previous.span;
+        self.current_chunk().write_chunk(byte, span);
+    }
+
+    // Attributes the byte to `span` rather than the last token, so runtime errors point at it
+    fn emit_byte_at(&mut self, byte: u8, span: Span) {
+        self.current_chunk().write_chunk(byte, span);
     }
 
     fn emit_bytes(&mut self, byte1: u8, byte2: u8) {
@@ -342,46 +671,208 @@ impl<'a> Parser<'a> {
         self.emit_byte(byte2);
     }
 
+    fn emit_loop(&mut self, loop_start: usize) {
+        self.emit_byte(OpCode::Loop as u8);
+
+        let offset = self.current_chunk().len() - loop_start + 2;
+        if offset > u16::MAX as usize {
+            self.error("Loop body too large.");
+        }
+
+        self.emit_byte(((offset >> 8) & 0xff) as u8);
+        self.emit_byte((offset & 0xff) as u8);
+    }
+
+    fn emit_jump(&mut self, instruction: OpCode) -> usize {
+        self.emit_byte(instruction as u8);
+        self.emit_byte(0xff);
+        self.emit_byte(0xff);
+        self.current_chunk().len() - 2
+    }
+
     fn emit_return(&mut self) {
+        if self.compiler.function_type == FunctionType::Initializer {
+            self.emit_bytes(OpCode::GetLocal as u8, 0);
+        } else {
+            self.emit_byte(OpCode::Nil as u8);
+        }
+
         self.emit_byte(OpCode::Return as u8);
     }
 
-    fn make_constant(&mut self, value: Value) -> u8 {
-        let constant = self.chunk.add_constant(value);
-        constant as u8
+    // Adds a constant and returns its index, which can need the long form of an instruction
+    fn make_constant(&mut self, value: Value) -> usize {
+        let constant = self.current_chunk().add_constant(value);
+        if constant > CONSTANT_LONG_MAX {
+            self.error("Too many constants in one chunk.");
+            return 0;
+        }
+        constant
     }
 
     fn emit_constant(&mut self, value: Value) {
         let constant = self.make_constant(value);
-        self.emit_bytes(OpCode::Constant as u8, constant);
+        self.emit_indexed(OpCode::Constant, constant);
+    }
+
+    // Emits `op` followed by an index operand, switching to the op's long variant when the index
+    // doesn't fit in a byte. Local and upvalue slots always do.
+    fn emit_indexed(&mut self, op: OpCode, index: usize) {
+        self.emit_indexed_at(op, index, self.previous.span);
     }
 
-    fn end_compiler(&mut self) {
+    fn emit_indexed_at(&mut self, op: OpCode, index: usize, span: Span) {
+        if let Ok(index) = u8::try_from(index) {
+            self.emit_byte_at(op as u8, span);
+            self.emit_byte(index);
+        } else {
+            self.emit_byte_at(op.long() as u8, span);
+            self.emit_byte((index >> 16) as u8);
+            self.emit_byte((index >> 8) as u8);
+            self.emit_byte(index as u8);
+        }
+    }
+
+    fn patch_jump(&mut self, offset: usize) {
+        // -2 to adjust for the bytecode for the jump offset itself
+        let jump = self.current_chunk().len() - offset - 2;
+
+        if jump > u16::MAX as usize {
+            self.error("Too much code to jump over.");
+        }
+
+        self.current_chunk()[offset] = ((jump >> 8) & 0xff) as u8;
+        self.current_chunk()[offset + 1] = (jump & 0xff) as u8;
+    }
+
+    fn end_compiler(&mut self) -> (*mut FunctionObj, Vec<Upvalue>) {
         self.emit_return();
 
-        if cfg!(feature = "debug-print-code") {
-            if !self.had_error {
-                disassemble_chunk(&self.chunk, "code");
+        let function = self.compiler.function;
+        let upvalues = mem::take(&mut self.compiler.upvalues);
+        unsafe {
+            (*function).upvalue_count = upvalues.len();
+
+            if cfg!(feature = "debug-print-code") && self.diagnostics.is_empty() {
+                disassemble_chunk(&(*function).chunk, &(*function).to_string());
             }
         }
+
+        if let Some(enclosing) = self.compiler.enclosing.take() {
+            self.compiler = *enclosing;
+        }
+        self.vm.pop_compiler_root();
+
+        (function, upvalues)
     }
 
     fn binary(&mut self) {
-        let operator_type = self.previous.token_type;
-        let rule = get_rule(operator_type);
-        self.parse_precedence(Precedence::try_from(rule.precedence as u8 + 1).unwrap());
-        match operator_type {
-            TokenType::BangEqual => self.emit_bytes(OpCode::Equal as u8, OpCode::Not as u8),
-            TokenType::EqualEqual => self.emit_byte(OpCode::Equal as u8),
-            TokenType::Greater => self.emit_byte(OpCode::Greater as u8),
-            TokenType::GreaterEqual => self.emit_bytes(OpCode::Less as u8, OpCode::Not as u8),
-            TokenType::Less => self.emit_byte(OpCode::Less as u8),
-            TokenType::LessEqual => self.emit_bytes(OpCode::Greater as u8, OpCode::Not as u8),
-            TokenType::Plus => self.emit_byte(OpCode::Add as u8),
-            TokenType::Minus => self.emit_byte(OpCode::Subtract as u8),
-            TokenType::Star => self.emit_byte(OpCode::Multiply as u8),
-            TokenType::Slash => self.emit_byte(OpCode::Divide as u8),
-            _ => (), // unreachable
+        let operator = self.previous;
+        let rule = get_rule(operator.token_type);
+        if operator.token_type == TokenType::StarStar {
+            // Right-associative, and the exponent can be negated, like `2 ** -1`
+            self.parse_precedence(Precedence::Unary);
+        } else {
+            self.parse_precedence(Precedence::try_from(rule.precedence as u8 + 1).unwrap());
+        }
+        let instructions: &[OpCode] = match operator.token_type {
+            TokenType::BangEqual => &[OpCode::Equal, OpCode::Not],
+            TokenType::EqualEqual => &[OpCode::Equal],
+            TokenType::Greater => &[OpCode::Greater],
+            TokenType::GreaterEqual => &[OpCode::Less, OpCode::Not],
+            TokenType::Less => &[OpCode::Less],
+            TokenType::In => &[OpCode::In],
+            TokenType::LessEqual => &[OpCode::Greater, OpCode::Not],
+            TokenType::Plus => &[OpCode::Add],
+            TokenType::Minus => &[OpCode::Subtract],
+            TokenType::Star => &[OpCode::Multiply],
+            TokenType::Slash => &[OpCode::Divide],
+            TokenType::TildeSlash => &[OpCode::IntDivide],
+            TokenType::Percent => &[OpCode::Modulo],
+            TokenType::StarStar => &[OpCode::Power],
+            TokenType::Ampersand => &[OpCode::BitAnd],
+            TokenType::Pipe => &[OpCode::BitOr],
+            TokenType::Caret => &[OpCode::BitXor],
+            TokenType::LessLess => &[OpCode::ShiftLeft],
+            TokenType::GreaterGreater => &[OpCode::ShiftRight],
+            _ => &[], // unreachable
+        };
+        for &instruction in instructions {
+            self.emit_byte_at(instruction as u8, operator.span);
+        }
+    }
+
+    fn and_(&mut self) {
+        let end_jump = self.emit_jump(OpCode::JumpIfFalse);
+
+        self.emit_byte(OpCode::Pop as u8);
+        self.parse_precedence(Precedence::And);
+
+        self.patch_jump(end_jump);
+    }
+
+    fn or_(&mut self) {
+        let else_jump = self.emit_jump(OpCode::JumpIfFalse);
+        let end_jump = self.emit_jump(OpCode::Jump);
+
+        self.patch_jump(else_jump);
+        self.emit_byte(OpCode::Pop as u8);
+
+        self.parse_precedence(Precedence::Or);
+        self.patch_jump(end_jump);
+    }
+
+    fn call(&mut self) {
+        let paren = self.previous.span;
+        let arg_count = self.argument_list();
+        self.emit_byte_at(OpCode::Call as u8, paren);
+        self.emit_byte(arg_count);
+    }
+
+    // Either bound of a slice like `list[1:]` can be left out
+    fn subscript(&mut self, can_assign: bool) {
+        let bracket = self.previous.span;
+
+        if self.check(TokenType::Colon) {
+            self.emit_byte(OpCode::Nil as u8);
+        } else {
+            self.expression();
+        }
+
+        if self.match_(TokenType::Colon) {
+            if self.check(TokenType::RightBracket) {
+                self.emit_byte(OpCode::Nil as u8);
+            } else {
+                self.expression();
+            }
+            self.consume(TokenType::RightBracket, "Expect ']' after slice.");
+            self.emit_byte_at(OpCode::Slice as u8, bracket);
+            return;
+        }
+
+        self.consume(TokenType::RightBracket, "Expect ']' after index.");
+        if can_assign && self.match_(TokenType::Equal) {
+            self.expression();
+            self.emit_byte_at(OpCode::IndexSet as u8, bracket);
+        } else {
+            self.emit_byte_at(OpCode::IndexGet as u8, bracket);
+        }
+    }
+
+    fn dot(&mut self, can_assign: bool) {
+        self.consume(TokenType::Identifier, "Expect property name after '.'.");
+        let span = self.previous.span;
+        let name = self.identifier_constant(self.previous);
+
+        if can_assign && self.match_(TokenType::Equal) {
+            self.expression();
+            self.emit_indexed_at(OpCode::SetProperty, name, span);
+        } else if self.match_(TokenType::LeftParen) {
+            let arg_count = self.argument_list();
+            self.emit_indexed_at(OpCode::Invoke, name, span);
+            self.emit_byte(arg_count);
+        } else {
+            self.emit_indexed(OpCode::GetProperty, name);
         }
     }
 
@@ -399,32 +890,176 @@ impl<'a> Parser<'a> {
         self.consume(TokenType::RightParen, "Expect ')' after expression.");
     }
 
+    fn list(&mut self) {
+        let mut item_count: usize = 0;
+        if !self.check(TokenType::RightBracket) {
+            loop {
+                self.expression();
+                if item_count == 255 {
+                    self.error("Can't have more than 255 items in a list literal.");
+                }
+                item_count += 1;
+                if !self.match_(TokenType::Comma) {
+                    break;
+                }
+            }
+        }
+        self.consume(TokenType::RightBracket, "Expect ']' after list items.");
+        self.emit_bytes(OpCode::BuildList as u8, item_count as u8);
+    }
+
+    // A `{` at the start of a statement is a block, so map literals only appear inside expressions
+    fn map(&mut self) {
+        let mut entry_count: usize = 0;
+        if !self.check(TokenType::RightBrace) {
+            loop {
+                self.expression();
+                self.consume(TokenType::Colon, "Expect ':' after map key.");
+                self.expression();
+                if entry_count == 255 {
+                    self.error("Can't have more than 255 entries in a map literal.");
+                }
+                entry_count += 1;
+                if !self.match_(TokenType::Comma) {
+                    break;
+                }
+            }
+        }
+        self.consume(TokenType::RightBrace, "Expect '}' after map entries.");
+        self.emit_bytes(OpCode::BuildMap as u8, entry_count as u8);
+    }
+
     fn number(&mut self) {
-        let value: f64 = self.previous.value.parse().unwrap();
-        self.emit_constant(Value::Number(value));
+        match parse_number(self.previous.value) {
+            Ok(value) => self.emit_constant(value),
+            Err(message) => self.error(message),
+        }
     }
 
     fn string(&mut self) {
-        let len = self.previous.value.len();
+        // An interpolation that's closed before it has an expression in it
+        if self.previous.value.starts_with('}') {
+            let span = Span {
+                len: 1,
+                ..self.previous.span
+            };
+            self.error_at(synthetic_token("}", span), "Expect expression.");
+            return;
+        }
+
+        let text = string_contents(self.previous);
+        self.emit_string(text);
+    }
 
-        // Remove heading and trailing quotation marks
-        let new_string = String::from(&self.previous.value[1..len - 1]);
+    // `"a ${b} c"` compiles to the concatenation `"a " + str(b) + " c"`
+    fn interpolation(&mut self) {
+        // Empty pieces are left out, since stringifying already gives a string to add to
+        let text = string_contents(self.previous);
+        let has_text = !text.is_empty();
+        if has_text {
+            self.emit_string(text);
+        }
 
-        self.emit_constant(Value::string(new_string.as_ptr(), len - 2));
+        self.expression();
+        self.emit_byte(OpCode::Stringify as u8);
+        if has_text {
+            self.emit_byte(OpCode::Add as u8);
+        }
 
-        // Make sure new_string has an owner
-        self.all_strings.push(new_string);
+        loop {
+            let more = self.match_(TokenType::Interpolation);
+            if !more && !self.match_(TokenType::String) {
+                self.error_at_current("Expect '}' after interpolation.");
+                return;
+            }
+
+            let text = string_contents(self.previous);
+            if !text.is_empty() {
+                self.emit_string(text);
+                self.emit_byte(OpCode::Add as u8);
+            }
+            if !more {
+                return;
+            }
+
+            self.expression();
+            self.emit_byte(OpCode::Stringify as u8);
+            self.emit_byte(OpCode::Add as u8);
+        }
+    }
+
+    fn emit_string(&mut self, text: &str) {
+        let string = self.copy_string(&unescape(text));
+        self.emit_constant(Value::Obj(Obj::StringObj(string)));
+    }
+
+    fn named_variable(&mut self, name: Token, can_assign: bool) {
+        let (get_op, set_op, arg) = if let Some(arg) = self.resolve_local(name) {
+            (OpCode::GetLocal, OpCode::SetLocal, arg as usize)
+        } else if let Some(arg) = self.resolve_upvalue(name) {
+            (OpCode::GetUpvalue, OpCode::SetUpvalue, arg as usize)
+        } else {
+            let arg = self.identifier_constant(name);
+            (OpCode::GetGlobal, OpCode::SetGlobal, arg)
+        };
+
+        if can_assign && self.match_(TokenType::Equal) {
+            self.expression();
+            self.emit_indexed_at(set_op, arg, name.span);
+        } else {
+            self.emit_indexed(get_op, arg);
+        }
+    }
+
+    fn variable(&mut self, can_assign: bool) {
+        self.named_variable(self.previous, can_assign);
+    }
+
+    fn super_(&mut self) {
+        match &self.class_compiler {
+            None => self.error("Can't use 'super' outside of a class."),
+            Some(class) if !class.has_superclass => {
+                self.error("Can't use 'super' in a class with no superclass.")
+            }
+            _ => (),
+        }
+
+        self.consume(TokenType::Dot, "Expect '.' after 'super'.");
+        self.consume(TokenType::Identifier, "Expect superclass method name.");
+        let span = self.previous.span;
+        let name = self.identifier_constant(self.previous);
+
+        self.named_variable(synthetic_token("this", span), false);
+        if self.match_(TokenType::LeftParen) {
+            let arg_count = self.argument_list();
+            self.named_variable(synthetic_token("super", span), false);
+            self.emit_indexed_at(OpCode::SuperInvoke, name, span);
+            self.emit_byte(arg_count);
+        } else {
+            self.named_variable(synthetic_token("super", span), false);
+            self.emit_indexed(OpCode::GetSuper, name);
+        }
+    }
+
+    fn this_(&mut self) {
+        if self.class_compiler.is_none() {
+            self.error("Can't use 'this' outside of a class.");
+            return;
+        }
+
+        self.variable(false);
     }
 
     fn unary(&mut self) {
-        let operator_type = self.previous.token_type;
+        let operator = self.previous;
         // Compile the operand
         self.parse_precedence(Precedence::Unary);
 
         // Emit the operator instruction
-        match operator_type {
-            TokenType::Bang => self.emit_byte(OpCode::Not as u8),
-            TokenType::Minus => self.emit_byte(OpCode::Negate as u8),
+        match operator.token_type {
+            TokenType::Bang => self.emit_byte_at(OpCode::Not as u8, operator.span),
+            TokenType::Minus => self.emit_byte_at(OpCode::Negate as u8, operator.span),
+            TokenType::Tilde => self.emit_byte_at(OpCode::BitNot as u8, operator.span),
             _ => (), // unreachable
         }
     }
@@ -435,24 +1070,488 @@ impl<'a> Parser<'a> {
         match prefix_rule {
             None => self.error("Expect expression."),
             Some(prefix_rule) => {
-                prefix_rule(self);
+                let can_assign = precedence <= Precedence::Assignment;
+                prefix_rule(self, can_assign);
 
                 while precedence <= get_rule(self.current.token_type).precedence {
                     self.advance();
                     let infix_rule = get_rule(self.previous.token_type).infix;
                     match infix_rule {
                         None => self.error("Expect expression."),
-                        Some(infix_rule) => infix_rule(self),
+                        Some(infix_rule) => infix_rule(self, can_assign),
                     }
                 }
+
+                if can_assign && self.match_(TokenType::Equal) {
+                    self.error("Invalid assignment target.");
+                }
+            }
+        }
+    }
+
+    #[inline]
+    fn copy_string(&mut self, value: &str) -> *mut StringObj {
+        unsafe { self.vm.copy_string(value) }
+    }
+
+    fn identifier_constant(&mut self, name: Token) -> usize {
+        let string = self.copy_string(name.value);
+
+        // Reuse the constant from an earlier use of the name rather than using up another slot
+        if let Some(&constant) = self.compiler.names.get(&string) {
+            return constant;
+        }
+        let constant = self.make_constant(Value::Obj(Obj::StringObj(string)));
+        self.compiler.names.insert(string, constant);
+        constant
+    }
+
+    fn resolve_local(&mut self, name: Token) -> Option<u8> {
+        self.compiler.resolve_local(name).unwrap_or_else(|message| {
+            self.error(message);
+            None
+        })
+    }
+
+    fn resolve_upvalue(&mut self, name: Token) -> Option<u8> {
+        self.compiler
+            .resolve_upvalue(name)
+            .unwrap_or_else(|message| {
+                self.error(message);
+                None
+            })
+    }
+
+    fn add_local(&mut self, name: Token<'a>) {
+        if self.compiler.locals.len() == UINT8_COUNT {
+            self.error("Too many local variables in function.");
+            return;
+        }
+
+        self.compiler.locals.push(Local {
+            name,
+            depth: None,
+            is_captured: false,
+        });
+    }
+
+    fn declare_variable(&mut self) {
+        if self.compiler.scope_depth == 0 {
+            return;
+        }
+
+        let name = self.previous;
+        let scope_depth = self.compiler.scope_depth;
+        let duplicate = self
+            .compiler
+            .locals
+            .iter()
+            .rev()
+            .take_while(|local| local.depth.is_none_or(|depth| depth >= scope_depth))
+            .find(|local| local.name.value == name.value)
+            .map(|local| local.name.span);
+
+        if let Some(previous) = duplicate {
+            let note = format!("Previously declared on line {}.", previous.line);
+            self.error_with_note("Already a variable with this name in this scope.", note);
+        }
+
+        self.add_local(name);
+    }
+
+    fn parse_variable(&mut self, error_message: &str) -> usize {
+        self.consume(TokenType::Identifier, error_message);
+
+        self.declare_variable();
+        if self.compiler.scope_depth > 0 {
+            return 0;
+        }
+
+        self.identifier_constant(self.previous)
+    }
+
+    fn mark_initialized(&mut self) {
+        if self.compiler.scope_depth == 0 {
+            return;
+        }
+        if let Some(local) = self.compiler.locals.last_mut() {
+            local.depth = Some(self.compiler.scope_depth);
+        }
+    }
+
+    fn define_variable(&mut self, global: usize) {
+        if self.compiler.scope_depth > 0 {
+            self.mark_initialized();
+            return;
+        }
+
+        self.emit_indexed(OpCode::DefineGlobal, global);
+    }
+
+    fn argument_list(&mut self) -> u8 {
+        let mut arg_count: usize = 0;
+        if !self.check(TokenType::RightParen) {
+            loop {
+                self.expression();
+                if arg_count == 255 {
+                    self.error("Can't have more than 255 arguments.");
+                }
+                arg_count += 1;
+                if !self.match_(TokenType::Comma) {
+                    break;
+                }
             }
         }
+        self.consume(TokenType::RightParen, "Expect ')' after arguments.");
+        arg_count as u8
     }
 
     fn expression(&mut self) {
         self.parse_precedence(Precedence::Assignment);
     }
 
+    fn block(&mut self) {
+        while !self.check(TokenType::RightBrace) && !self.check(TokenType::Eof) {
+            self.declaration(false);
+        }
+
+        self.consume(TokenType::RightBrace, "Expect '}' after block.");
+    }
+
+    fn begin_scope(&mut self) {
+        self.compiler.scope_depth += 1;
+    }
+
+    fn end_scope(&mut self) {
+        self.compiler.scope_depth -= 1;
+
+        while let Some(local) = self.compiler.locals.last() {
+            if local
+                .depth
+                .is_none_or(|depth| depth <= self.compiler.scope_depth)
+            {
+                break;
+            }
+            if local.is_captured {
+                self.emit_byte(OpCode::CloseUpvalue as u8);
+            } else {
+                self.emit_byte(OpCode::Pop as u8);
+            }
+            self.compiler.locals.pop();
+        }
+    }
+
+    fn function(&mut self, function_type: FunctionType) {
+        let function = unsafe { self.vm.new_function() };
+        unsafe { (*function).chunk.source = Arc::clone(&self.source) };
+        let compiler = Compiler::new(function_type, function);
+        let enclosing = mem::replace(&mut self.compiler, compiler);
+        self.compiler.enclosing = Some(Box::new(enclosing));
+
+        // The function is already rooted, so the name can't be collected out from under it
+        let name = self.copy_string(self.previous.value);
+        unsafe { (*function).name = Some(name) };
+
+        self.begin_scope();
+
+        self.consume(TokenType::LeftParen, "Expect '(' after function name.");
+        if !self.check(TokenType::RightParen) {
+            loop {
+                let arity = unsafe {
+                    (*function).arity += 1;
+                    (*function).arity
+                };
+                if arity > 255 {
+                    self.error_at_current("Can't have more than 255 parameters.");
+                }
+                let constant = self.parse_variable("Expect parameter name.");
+                self.define_variable(constant);
+                if !self.match_(TokenType::Comma) {
+                    break;
+                }
+            }
+        }
+        self.consume(TokenType::RightParen, "Expect ')' after parameters.");
+        self.consume(TokenType::LeftBrace, "Expect '{' before function body.");
+        self.block();
+
+        let (function, upvalues) = self.end_compiler();
+        let constant = self.make_constant(Value::Obj(Obj::FunctionObj(function)));
+        self.emit_indexed(OpCode::Closure, constant);
+
+        for upvalue in upvalues {
+            self.emit_byte(upvalue.is_local as u8);
+            self.emit_byte(upvalue.index);
+        }
+    }
+
+    fn method(&mut self) {
+        self.consume(TokenType::Identifier, "Expect method name.");
+        let constant = self.identifier_constant(self.previous);
+
+        let function_type = if self.previous.value == "init" {
+            FunctionType::Initializer
+        } else {
+            FunctionType::Method
+        };
+        self.function(function_type);
+        self.emit_indexed(OpCode::Method, constant);
+    }
+
+    fn class_declaration(&mut self) {
+        self.consume(TokenType::Identifier, "Expect class name.");
+        let class_name = self.previous;
+        let name_constant = self.identifier_constant(self.previous);
+        self.declare_variable();
+
+        self.emit_indexed(OpCode::Class, name_constant);
+        self.define_variable(name_constant);
+
+        let enclosing = self.class_compiler.take();
+        self.class_compiler = Some(Box::new(ClassCompiler {
+            enclosing,
+            has_superclass: false,
+        }));
+
+        if self.match_(TokenType::Less) {
+            self.consume(TokenType::Identifier, "Expect superclass name.");
+            self.variable(false);
+
+            if class_name.value == self.previous.value {
+                self.error("A class can't inherit from itself.");
+            }
+
+            // Each subclass gets its own scope holding `super`, so methods can capture it
+            self.begin_scope();
+            self.add_local(synthetic_token("super", self.previous.span));
+            self.define_variable(0);
+
+            self.named_variable(class_name, false);
+            self.emit_byte(OpCode::Inherit as u8);
+            if let Some(class) = self.class_compiler.as_mut() {
+                class.has_superclass = true;
+            }
+        }
+
+        // Load the class back onto the stack so the methods can be bound to it
+        self.named_variable(class_name, false);
+        self.consume(TokenType::LeftBrace, "Expect '{' before class body.");
+        while !self.check(TokenType::RightBrace) && !self.check(TokenType::Eof) {
+            self.method();
+        }
+        self.consume(TokenType::RightBrace, "Expect '}' after class body.");
+        self.emit_byte(OpCode::Pop as u8);
+
+        if self
+            .class_compiler
+            .as_ref()
+            .is_some_and(|class| class.has_superclass)
+        {
+            self.end_scope();
+        }
+
+        self.class_compiler = self.class_compiler.take().and_then(|class| class.enclosing);
+    }
+
+    fn fun_declaration(&mut self) {
+        let global = self.parse_variable("Expect function name.");
+        self.mark_initialized();
+        self.function(FunctionType::Function);
+        self.define_variable(global);
+    }
+
+    fn expression_statement(&mut self, is_top_level: bool) {
+        self.expression();
+        self.consume(TokenType::Semicolon, "Expect ';' after expression.");
+
+        // A script ending in an expression statement evaluates to that expression's value
+        if is_top_level && self.check(TokenType::Eof) {
+            self.emit_byte(OpCode::Return as u8);
+        } else {
+            self.emit_byte(OpCode::Pop as u8);
+        }
+    }
+
+    fn var_declaration(&mut self) {
+        let global = self.parse_variable("Expect variable name.");
+
+        if self.match_(TokenType::Equal) {
+            self.expression();
+        } else {
+            self.emit_byte(OpCode::Nil as u8);
+        }
+        self.consume(
+            TokenType::Semicolon,
+            "Expect ';' after variable declaration.",
+        );
+
+        self.define_variable(global);
+    }
+
+    fn for_statement(&mut self) {
+        self.begin_scope();
+        self.consume(TokenType::LeftParen, "Expect '(' after 'for'.");
+        if self.match_(TokenType::Semicolon) {
+            // No initializer
+        } else if self.match_(TokenType::Var) {
+            self.var_declaration();
+        } else {
+            self.expression_statement(false);
+        }
+
+        let mut loop_start = self.current_chunk().len();
+        let mut exit_jump = None;
+        if !self.match_(TokenType::Semicolon) {
+            self.expression();
+            self.consume(TokenType::Semicolon, "Expect ';' after loop condition.");
+
+            // Jump out of the loop if the condition is false
+            exit_jump = Some(self.emit_jump(OpCode::JumpIfFalse));
+            self.emit_byte(OpCode::Pop as u8); // Condition
+        }
+
+        if !self.match_(TokenType::RightParen) {
+            let body_jump = self.emit_jump(OpCode::Jump);
+            let increment_start = self.current_chunk().len();
+            self.expression();
+            self.emit_byte(OpCode::Pop as u8);
+            self.consume(TokenType::RightParen, "Expect ')' after for clauses.");
+
+            self.emit_loop(loop_start);
+            loop_start = increment_start;
+            self.patch_jump(body_jump);
+        }
+
+        self.statement(false);
+        self.emit_loop(loop_start);
+
+        if let Some(exit_jump) = exit_jump {
+            self.patch_jump(exit_jump);
+            self.emit_byte(OpCode::Pop as u8); // Condition
+        }
+
+        self.end_scope();
+    }
+
+    fn if_statement(&mut self) {
+        self.consume(TokenType::LeftParen, "Expect '(' after 'if'.");
+        self.expression();
+        self.consume(TokenType::RightParen, "Expect ')' after condition.");
+
+        let then_jump = self.emit_jump(OpCode::JumpIfFalse);
+        self.emit_byte(OpCode::Pop as u8);
+        self.statement(false);
+
+        let else_jump = self.emit_jump(OpCode::Jump);
+
+        self.patch_jump(then_jump);
+        self.emit_byte(OpCode::Pop as u8);
+
+        if self.match_(TokenType::Else) {
+            self.statement(false);
+        }
+        self.patch_jump(else_jump);
+    }
+
+    fn print_statement(&mut self) {
+        self.expression();
+        self.consume(TokenType::Semicolon, "Expect ';' after value.");
+        self.emit_byte(OpCode::Print as u8);
+    }
+
+    fn return_statement(&mut self) {
+        if self.compiler.function_type == FunctionType::Script {
+            self.error("Can't return from top-level code.");
+        }
+
+        if self.match_(TokenType::Semicolon) {
+            self.emit_return();
+        } else {
+            if self.compiler.function_type == FunctionType::Initializer {
+                self.error("Can't return a value from an initializer.");
+            }
+
+            self.expression();
+            self.consume(TokenType::Semicolon, "Expect ';' after return value.");
+            self.emit_byte(OpCode::Return as u8);
+        }
+    }
+
+    fn while_statement(&mut self) {
+        let loop_start = self.current_chunk().len();
+        self.consume(TokenType::LeftParen, "Expect '(' after 'while'.");
+        self.expression();
+        self.consume(TokenType::RightParen, "Expect ')' after condition.");
+
+        let exit_jump = self.emit_jump(OpCode::JumpIfFalse);
+        self.emit_byte(OpCode::Pop as u8);
+        self.statement(false);
+        self.emit_loop(loop_start);
+
+        self.patch_jump(exit_jump);
+        self.emit_byte(OpCode::Pop as u8);
+    }
+
+    fn synchronize(&mut self) {
+        self.panic_mode = false;
+
+        while self.current.token_type != TokenType::Eof {
+            if self.previous.token_type == TokenType::Semicolon {
+                return;
+            }
+            match self.current.token_type {
+                TokenType::Class
+                | TokenType::Fun
+                | TokenType::Var
+                | TokenType::For
+                | TokenType::If
+                | TokenType::While
+                | TokenType::Print
+                | TokenType::Return => return,
+                _ => (), // Do nothing
+            }
+            self.advance();
+        }
+    }
+
+    // `is_top_level` is set for declarations directly in the script body, not nested in a block
+    // or statement
+    fn declaration(&mut self, is_top_level: bool) {
+        if self.match_(TokenType::Class) {
+            self.class_declaration();
+        } else if self.match_(TokenType::Fun) {
+            self.fun_declaration();
+        } else if self.match_(TokenType::Var) {
+            self.var_declaration();
+        } else {
+            self.statement(is_top_level);
+        }
+
+        if self.panic_mode {
+            self.synchronize();
+        }
+    }
+
+    fn statement(&mut self, is_top_level: bool) {
+        if self.match_(TokenType::Print) {
+            self.print_statement();
+        } else if self.match_(TokenType::For) {
+            self.for_statement();
+        } else if self.match_(TokenType::If) {
+            self.if_statement();
+        } else if self.match_(TokenType::Return) {
+            self.return_statement();
+        } else if self.match_(TokenType::While) {
+            self.while_statement();
+        } else if self.match_(TokenType::LeftBrace) {
+            self.begin_scope();
+            self.block();
+            self.end_scope();
+        } else {
+            self.expression_statement(is_top_level);
+        }
+    }
+
     fn error_at_current(&mut self, message: &str) {
         self.error_at(self.current, message);
     }
@@ -463,19 +1562,28 @@ impl<'a> Parser<'a> {
         }
         self.panic_mode = true;
 
-        eprint!("[line {}] Error", token.line);
+        let mut diagnostic = Diagnostic::error(message, token.span);
+        diagnostic.location = match token.token_type {
+            TokenType::Eof => Some("end".to_string()),
+            // The message already says what's wrong with the text
+            TokenType::Error => None,
+            _ => Some(format!("'{}'", token.value)),
+        };
+        self.diagnostics.push(diagnostic);
+    }
 
-        match token.token_type {
-            TokenType::Eof => eprint!(" at end"),
-            TokenType::Error => (),
-            _ => eprint!(" at '{}'", token.value),
+    fn error_with_note(&mut self, message: &str, note: String) {
+        if self.panic_mode {
+            return;
         }
 
-        eprintln!(": {}", message);
-        self.had_error = true;
+        self.error(message);
+        if let Some(diagnostic) = self.diagnostics.last_mut() {
+            diagnostic.notes.push(note);
+        }
     }
 
     fn error(&mut self, message: &str) {
-        self.error_at(self.current, message);
+        self.error_at(self.previous, message);
     }
 }
@@ -1,4 +1,6 @@
 use crate::chunk::{Chunk, OpCode};
+use crate::object::Obj;
+use crate::value::Value;
 
 pub fn disassemble_chunk(chunk: &Chunk, name: &str) {
     println!("=== {} ===", name);
@@ -13,10 +15,11 @@ pub fn disassemble_chunk(chunk: &Chunk, name: &str) {
 pub fn disassemble_instruction(chunk: &Chunk, offset: usize) -> usize {
     print!("{:04} ", offset);
 
-    if offset > 0 && chunk.lines[offset] == chunk.lines[offset - 1] {
+    let line = chunk.get_line(offset);
+    if offset > 0 && line == chunk.get_line(offset - 1) {
         print!("   | ");
     } else {
-        print!("{:>4} ", chunk.lines[offset]);
+        print!("{:>4} ", line);
     }
 
     let byte = chunk[offset];
@@ -24,19 +27,72 @@ pub fn disassemble_instruction(chunk: &Chunk, offset: usize) -> usize {
     match instruction {
         Ok(opcode) => match opcode {
             OpCode::Constant => constant_instruction("OP_CONSTANT", chunk, offset),
+            OpCode::ConstantLong => constant_instruction("OP_CONSTANT_LONG", chunk, offset),
             OpCode::Nil => simple_instruction("OP_NIL", offset),
             OpCode::True => simple_instruction("OP_TRUE", offset),
             OpCode::False => simple_instruction("OP_FALSE", offset),
+            OpCode::Pop => simple_instruction("OP_POP", offset),
+            OpCode::GetLocal => byte_instruction("OP_GET_LOCAL", chunk, offset),
+            OpCode::SetLocal => byte_instruction("OP_SET_LOCAL", chunk, offset),
+            OpCode::GetGlobal => constant_instruction("OP_GET_GLOBAL", chunk, offset),
+            OpCode::GetGlobalLong => constant_instruction("OP_GET_GLOBAL_LONG", chunk, offset),
+            OpCode::DefineGlobal => constant_instruction("OP_DEFINE_GLOBAL", chunk, offset),
+            OpCode::DefineGlobalLong => {
+                constant_instruction("OP_DEFINE_GLOBAL_LONG", chunk, offset)
+            }
+            OpCode::SetGlobal => constant_instruction("OP_SET_GLOBAL", chunk, offset),
+            OpCode::SetGlobalLong => constant_instruction("OP_SET_GLOBAL_LONG", chunk, offset),
+            OpCode::GetUpvalue => byte_instruction("OP_GET_UPVALUE", chunk, offset),
+            OpCode::SetUpvalue => byte_instruction("OP_SET_UPVALUE", chunk, offset),
+            OpCode::GetProperty => constant_instruction("OP_GET_PROPERTY", chunk, offset),
+            OpCode::GetPropertyLong => constant_instruction("OP_GET_PROPERTY_LONG", chunk, offset),
+            OpCode::SetProperty => constant_instruction("OP_SET_PROPERTY", chunk, offset),
+            OpCode::SetPropertyLong => constant_instruction("OP_SET_PROPERTY_LONG", chunk, offset),
+            OpCode::GetSuper => constant_instruction("OP_GET_SUPER", chunk, offset),
+            OpCode::GetSuperLong => constant_instruction("OP_GET_SUPER_LONG", chunk, offset),
+            OpCode::BuildList => byte_instruction("OP_BUILD_LIST", chunk, offset),
+            OpCode::BuildMap => byte_instruction("OP_BUILD_MAP", chunk, offset),
+            OpCode::IndexGet => simple_instruction("OP_INDEX_GET", offset),
+            OpCode::IndexSet => simple_instruction("OP_INDEX_SET", offset),
+            OpCode::Slice => simple_instruction("OP_SLICE", offset),
             OpCode::Equal => simple_instruction("OP_EQUAL", offset),
             OpCode::Greater => simple_instruction("OP_GREATER", offset),
             OpCode::Less => simple_instruction("OP_LESS", offset),
+            OpCode::In => simple_instruction("OP_IN", offset),
             OpCode::Add => simple_instruction("OP_ADD", offset),
             OpCode::Subtract => simple_instruction("OP_SUBTRACT", offset),
             OpCode::Multiply => simple_instruction("OP_MULTIPLY", offset),
             OpCode::Divide => simple_instruction("OP_DIVIDE", offset),
+            OpCode::IntDivide => simple_instruction("OP_INT_DIVIDE", offset),
+            OpCode::Modulo => simple_instruction("OP_MODULO", offset),
+            OpCode::Power => simple_instruction("OP_POWER", offset),
+            OpCode::BitAnd => simple_instruction("OP_BIT_AND", offset),
+            OpCode::BitOr => simple_instruction("OP_BIT_OR", offset),
+            OpCode::BitXor => simple_instruction("OP_BIT_XOR", offset),
+            OpCode::ShiftLeft => simple_instruction("OP_SHIFT_LEFT", offset),
+            OpCode::ShiftRight => simple_instruction("OP_SHIFT_RIGHT", offset),
             OpCode::Not => simple_instruction("OP_NOT", offset),
             OpCode::Negate => simple_instruction("OP_NEGATE", offset),
+            OpCode::BitNot => simple_instruction("OP_BIT_NOT", offset),
+            OpCode::Stringify => simple_instruction("OP_STRINGIFY", offset),
+            OpCode::Print => simple_instruction("OP_PRINT", offset),
+            OpCode::Jump => jump_instruction("OP_JUMP", 1, chunk, offset),
+            OpCode::JumpIfFalse => jump_instruction("OP_JUMP_IF_FALSE", 1, chunk, offset),
+            OpCode::Loop => jump_instruction("OP_LOOP", -1, chunk, offset),
+            OpCode::Call => byte_instruction("OP_CALL", chunk, offset),
+            OpCode::Invoke => invoke_instruction("OP_INVOKE", chunk, offset),
+            OpCode::InvokeLong => invoke_instruction("OP_INVOKE_LONG", chunk, offset),
+            OpCode::SuperInvoke => invoke_instruction("OP_SUPER_INVOKE", chunk, offset),
+            OpCode::SuperInvokeLong => invoke_instruction("OP_SUPER_INVOKE_LONG", chunk, offset),
+            OpCode::Closure => closure_instruction("OP_CLOSURE", chunk, offset),
+            OpCode::ClosureLong => closure_instruction("OP_CLOSURE_LONG", chunk, offset),
+            OpCode::CloseUpvalue => simple_instruction("OP_CLOSE_UPVALUE", offset),
             OpCode::Return => simple_instruction("OP_RETURN", offset),
+            OpCode::Class => constant_instruction("OP_CLASS", chunk, offset),
+            OpCode::ClassLong => constant_instruction("OP_CLASS_LONG", chunk, offset),
+            OpCode::Inherit => simple_instruction("OP_INHERIT", offset),
+            OpCode::Method => constant_instruction("OP_METHOD", chunk, offset),
+            OpCode::MethodLong => constant_instruction("OP_METHOD_LONG", chunk, offset),
         },
         Err(_) => {
             println!("Unknown opcode {}\n", byte);
@@ -45,14 +101,70 @@ pub fn disassemble_instruction(chunk: &Chunk, offset: usize) -> usize {
     }
 }
 
+// The constant index operand of the instruction at `offset`, and the offset just past it
+fn constant_operand(chunk: &Chunk, offset: usize) -> (usize, usize) {
+    if OpCode::try_from(chunk[offset]).is_ok_and(OpCode::is_long) {
+        let constant = (chunk[offset + 1] as usize) << 16
+            | (chunk[offset + 2] as usize) << 8
+            | chunk[offset + 3] as usize;
+        (constant, offset + 4)
+    } else {
+        (chunk[offset + 1] as usize, offset + 2)
+    }
+}
+
 fn constant_instruction(name: &str, chunk: &Chunk, offset: usize) -> usize {
-    let constant = chunk[offset + 1] as usize;
+    let (constant, offset) = constant_operand(chunk, offset);
     print!("{:<16} {:4} '", name, constant);
     print!("{}", chunk.constants[constant]);
     println!("'");
+    offset
+}
+
+fn byte_instruction(name: &str, chunk: &Chunk, offset: usize) -> usize {
+    let slot = chunk[offset + 1];
+    println!("{:<16} {:4}", name, slot);
     offset + 2
 }
 
+fn closure_instruction(name: &str, chunk: &Chunk, offset: usize) -> usize {
+    let (constant, mut offset) = constant_operand(chunk, offset);
+    let value = chunk.constants[constant];
+    println!("{:<16} {:4} {}", name, constant, value);
+
+    if let Value::Obj(Obj::FunctionObj(function)) = value {
+        let upvalue_count = unsafe { (*function).upvalue_count };
+        for _ in 0..upvalue_count {
+            let is_local = chunk[offset];
+            let index = chunk[offset + 1];
+            println!(
+                "{:04}      |                     {} {}",
+                offset,
+                if is_local == 1 { "local" } else { "upvalue" },
+                index
+            );
+            offset += 2;
+        }
+    }
+    offset
+}
+
+fn invoke_instruction(name: &str, chunk: &Chunk, offset: usize) -> usize {
+    let (constant, offset) = constant_operand(chunk, offset);
+    let arg_count = chunk[offset];
+    print!("{:<16} ({} args) {:4} '", name, arg_count, constant);
+    print!("{}", chunk.constants[constant]);
+    println!("'");
+    offset + 1
+}
+
+fn jump_instruction(name: &str, sign: isize, chunk: &Chunk, offset: usize) -> usize {
+    let jump = ((chunk[offset + 1] as u16) << 8) | chunk[offset + 2] as u16;
+    let target = offset as isize + 3 + sign * jump as isize;
+    println!("{:<16} {:4} -> {}", name, offset, target);
+    offset + 3
+}
+
 fn simple_instruction(name: &str, offset: usize) -> usize {
     println!("{}", name);
     offset + 1
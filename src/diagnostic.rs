@@ -0,0 +1,109 @@
+use crate::scanner::Span;
+use std::fmt;
+use std::io::{self, Write};
+
+const RED: &str = "\x1b[1;31m";
+const BLUE: &str = "\x1b[1;34m";
+const RESET: &str = "\x1b[0m";
+
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub enum Severity {
+    Error,
+}
+
+// A problem found in a script, kept as data so the host decides how to show it
+#[derive(Debug)]
+pub struct Diagnostic {
+    pub severity: Severity,
+    pub message: String,
+    pub span: Span,
+    // What the error was found at, like `'x'` or `end`, if it's worth mentioning
+    pub location: Option<String>,
+    // Extra context, such as where a conflicting declaration is
+    pub notes: Vec<String>,
+}
+
+impl fmt::Display for Severity {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Severity::Error => write!(f, "Error"),
+        }
+    }
+}
+
+// Formats the `[line N] Error at 'x': message` header
+impl fmt::Display for Diagnostic {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "[line {}] {}", self.span.line, self.severity)?;
+        if let Some(location) = &self.location {
+            write!(f, " at {}", location)?;
+        }
+        write!(f, ": {}", self.message)
+    }
+}
+
+impl Diagnostic {
+    pub fn error(message: &str, span: Span) -> Self {
+        Diagnostic {
+            severity: Severity::Error,
+            message: message.to_string(),
+            span,
+            location: None,
+            notes: Vec::new(),
+        }
+    }
+
+    // Writes the header followed by the offending line of `source`
+    pub fn render(&self, out: &mut dyn Write, source: &str, color: bool) -> io::Result<()> {
+        writeln!(out, "{}", self)?;
+        render_snippet(out, source, self.span, color)?;
+        for note in self.notes.iter() {
+            writeln!(out, "  = note: {}", note)?;
+        }
+        Ok(())
+    }
+}
+
+// Writes the source line containing `span`, with the span underlined like `^~~~`
+pub fn render_snippet(
+    out: &mut dyn Write,
+    source: &str,
+    span: Span,
+    color: bool,
+) -> io::Result<()> {
+    if span.line == 0 || span.start > source.len() {
+        return Ok(());
+    }
+
+    let line_start = source[..span.start].rfind('\n').map_or(0, |i| i + 1);
+    let line_end = source[span.start..]
+        .find('\n')
+        .map_or(source.len(), |i| span.start + i);
+    let text = source[line_start..line_end].trim_end_matches('\r');
+
+    // Keep tabs in the padding so the underline lines up with the text above it
+    let padding: String = source[line_start..span.start]
+        .chars()
+        .map(|c| if c == '\t' { '\t' } else { ' ' })
+        .collect();
+
+    // Only the first line of a multi-line span is shown
+    let end = (span.start + span.len).min(line_end);
+    let width = source[span.start..end].chars().count().max(1);
+    let underline = format!("^{}", "~".repeat(width - 1));
+
+    let (red, blue, reset) = if color {
+        (RED, BLUE, RESET)
+    } else {
+        ("", "", "")
+    };
+
+    let gutter = " ".repeat(span.line.to_string().len());
+    writeln!(out, "{}{} |{}", blue, gutter, reset)?;
+    writeln!(out, "{}{} |{} {}", blue, span.line, reset, text)?;
+    writeln!(
+        out,
+        "{}{} |{} {}{}{}{}",
+        blue, gutter, reset, padding, red, underline, reset
+    )
+}
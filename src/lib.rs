@@ -0,0 +1,347 @@
+mod chunk;
+mod common;
+mod compiler;
+mod debug;
+mod diagnostic;
+mod memory;
+mod native;
+mod object;
+mod scanner;
+mod table;
+mod value;
+mod vm;
+
+pub use diagnostic::{Diagnostic, Severity};
+pub use scanner::Span;
+pub use vm::{RuntimeError, TraceFrame};
+
+use object::Obj;
+use std::fmt;
+use std::io::{self, IsTerminal, Write};
+use vm::{InterpretResult, VM};
+
+// A Lox value copied out of the VM, so the host never holds a pointer the collector could free
+#[derive(Clone, Debug, PartialEq)]
+pub enum Value {
+    Nil,
+    Bool(bool),
+    Int(i64),
+    // A floating point number
+    Number(f64),
+    String(String),
+    // Any other object, as `print` would show it. These can't be passed back into the VM.
+    Object(String),
+}
+
+#[derive(Debug)]
+pub enum LoxError {
+    Compile(Vec<Diagnostic>),
+    Runtime(Box<RuntimeError>),
+}
+
+// An embeddable Lox interpreter. Globals persist across calls to `eval`.
+pub struct Vm {
+    vm: VM,
+    // Where `report` writes diagnostics to
+    errors: Box<dyn Write>,
+    // Only stderr gets colored, and only when it's a terminal
+    color: bool,
+}
+
+impl fmt::Display for Value {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Value::Nil => write!(f, "nil"),
+            Value::Bool(value) => write!(f, "{}", value),
+            Value::Int(value) => write!(f, "{}", value),
+            Value::Number(value) => value::fmt_float(*value, f),
+            Value::String(value) | Value::Object(value) => write!(f, "{}", value),
+        }
+    }
+}
+
+impl LoxError {
+    // The sysexits.h code the command line interpreter exits with
+    pub fn exit_code(&self) -> i32 {
+        match self {
+            LoxError::Compile(_) => 65,
+            LoxError::Runtime(_) => 70,
+        }
+    }
+
+    // `source` is the script that was passed to `eval`
+    pub fn render(&self, out: &mut dyn Write, source: &str, color: bool) -> io::Result<()> {
+        match self {
+            LoxError::Compile(diagnostics) => {
+                for diagnostic in diagnostics.iter() {
+                    diagnostic.render(out, source, color)?;
+                }
+                Ok(())
+            }
+            LoxError::Runtime(error) => error.render(out, color),
+        }
+    }
+}
+
+impl fmt::Display for LoxError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            LoxError::Compile(diagnostics) => {
+                for (i, diagnostic) in diagnostics.iter().enumerate() {
+                    if i > 0 {
+                        writeln!(f)?;
+                    }
+                    write!(f, "{}", diagnostic)?;
+                }
+                Ok(())
+            }
+            LoxError::Runtime(error) => write!(f, "{}", error),
+        }
+    }
+}
+
+impl std::error::Error for LoxError {}
+
+impl Vm {
+    pub fn new() -> Self {
+        Vm {
+            vm: VM::new(),
+            errors: Box::new(io::stderr()),
+            color: io::stderr().is_terminal(),
+        }
+    }
+
+    // Sends the output of `print` statements to `output` instead of stdout
+    pub fn with_output(mut self, output: impl Write + 'static) -> Self {
+        self.vm = self.vm.with_output(Box::new(output));
+        self
+    }
+
+    // Sends diagnostics written by `report` to `errors` instead of stderr
+    pub fn with_error_output(mut self, errors: impl Write + 'static) -> Self {
+        self.errors = Box::new(errors);
+        self.color = false;
+        self
+    }
+
+    // Writes `error` to the error output. `source` is the script that was passed to `eval`.
+    pub fn report(&mut self, error: &LoxError, source: &str) -> io::Result<()> {
+        error.render(&mut self.errors, source, self.color)
+    }
+
+    // Runs `source` as a script. If it ends with an expression statement, that's the result.
+    pub fn eval(&mut self, source: &str) -> Result<Value, LoxError> {
+        unsafe { into_result(self.vm.interpret(source)) }
+    }
+
+    pub fn get_global(&self, name: &str) -> Option<Value> {
+        self.vm
+            .get_global(name)
+            .map(|value| unsafe { export_value(value) })
+    }
+
+    pub fn set_global(&mut self, name: &str, value: Value) -> Result<(), LoxError> {
+        unsafe {
+            let value = self.import_value(&value)?;
+            self.vm.set_global(name, value);
+        }
+        Ok(())
+    }
+
+    // Calls the global function or class `name` with `args`
+    pub fn call(&mut self, name: &str, args: &[Value]) -> Result<Value, LoxError> {
+        unsafe {
+            let callee = match self.vm.get_global(name) {
+                Some(callee) => callee,
+                None => {
+                    let message = format!("Undefined variable '{}'.", name);
+                    return Err(LoxError::Runtime(self.vm.runtime_error(&message)));
+                }
+            };
+
+            if !self.vm.has_stack_room(args.len() + 1) {
+                return Err(LoxError::Runtime(self.vm.runtime_error("Stack overflow.")));
+            }
+
+            // Everything stays on the stack while the remaining arguments are allocated
+            self.vm.push(callee);
+            for arg in args.iter() {
+                let arg = self.import_value(arg)?;
+                self.vm.push(arg);
+            }
+
+            into_result(self.vm.call_from_host(args.len()))
+        }
+    }
+
+    // Exposes `function` to Lox as a global called `name`. Its arguments and result are copied
+    // across the boundary the same way as for `call`, and an error becomes a runtime error.
+    pub fn define_native(
+        &mut self,
+        name: &str,
+        arity: usize,
+        function: impl Fn(&[Value]) -> Result<Value, String> + 'static,
+    ) {
+        self.vm.define_native(name, arity, move |vm, args| unsafe {
+            let args: Vec<Value> = args.iter().map(|&arg| export_value(arg)).collect();
+            let result = function(&args)?;
+            import_into(vm, &result).map_err(str::to_string)
+        });
+    }
+
+    unsafe fn import_value(&mut self, value: &Value) -> Result<value::Value, LoxError> {
+        import_into(&mut self.vm, value)
+            .map_err(|message| LoxError::Runtime(self.vm.runtime_error(message)))
+    }
+}
+
+impl Default for Vm {
+    fn default() -> Self {
+        Vm::new()
+    }
+}
+
+unsafe fn into_result(result: InterpretResult) -> Result<Value, LoxError> {
+    match result {
+        InterpretResult::Ok(value) => Ok(export_value(value)),
+        InterpretResult::CompileErr(diagnostics) => Err(LoxError::Compile(diagnostics)),
+        InterpretResult::RuntimeErr(error) => Err(LoxError::Runtime(error)),
+    }
+}
+
+unsafe fn import_into(vm: &mut VM, value: &Value) -> Result<value::Value, &'static str> {
+    match value {
+        Value::Nil => Ok(value::Value::Nil),
+        Value::Bool(value) => Ok(value::Value::Bool(*value)),
+        Value::Int(value) => Ok(value::Value::Int(*value)),
+        Value::Number(value) => Ok(value::Value::Number(*value)),
+        Value::String(value) => {
+            let string = vm.copy_string(value);
+            Ok(value::Value::Obj(Obj::StringObj(string)))
+        }
+        Value::Object(_) => Err("Only nil, booleans, numbers and strings can be passed to Lox."),
+    }
+}
+
+// Must be called before anything else is allocated, while `value` is still alive
+unsafe fn export_value(value: value::Value) -> Value {
+    match value {
+        value::Value::Nil => Value::Nil,
+        value::Value::Bool(value) => Value::Bool(value),
+        value::Value::Int(value) => Value::Int(value),
+        value::Value::Number(value) => Value::Number(value),
+        value::Value::Obj(Obj::StringObj(string)) => Value::String((*string).as_str().to_string()),
+        value::Value::Obj(obj) => Value::Object(obj.to_string()),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn call_leaves_stack_as_it_was() {
+        let mut vm = Vm::new();
+        vm.eval("fun add(a, b) { return a + b; }").unwrap();
+        let before = vm.vm.stack_len();
+
+        // More calls than the stack has slots, in case anything is left behind
+        for i in 0..20_000 {
+            let sum = vm.call("add", &[Value::Int(i), Value::Int(2)]).unwrap();
+            assert_eq!(sum, Value::Int(i + 2));
+        }
+        assert_eq!(vm.vm.stack_len(), before);
+
+        // Natives and classes return without pushing a frame
+        vm.eval("class Point {}").unwrap();
+        vm.call("str", &[Value::Int(1)]).unwrap();
+        vm.call("Point", &[]).unwrap();
+        assert_eq!(vm.vm.stack_len(), before);
+    }
+
+    #[test]
+    fn host_natives_are_callable_from_scripts() {
+        let mut vm = Vm::new();
+        vm.define_native("greet", 1, |args| match &args[0] {
+            Value::String(name) => Ok(Value::String(format!("hello {}", name))),
+            _ => Err("Expected a name.".to_string()),
+        });
+
+        let result = vm.eval(r#"greet("lox") + "!";"#).unwrap();
+        assert_eq!(result, Value::String("hello lox!".to_string()));
+
+        let error = vm.eval("greet(1);").unwrap_err();
+        assert_eq!(error.to_string(), "Expected a name.\n[line 1] in script");
+        let error = vm.eval("greet();").unwrap_err();
+        assert!(error
+            .to_string()
+            .contains("Expected 1 arguments but got 0."));
+    }
+
+    #[test]
+    fn errors_can_be_sent_between_threads() {
+        fn assert_send_sync<T: Send + Sync + 'static>() {}
+        assert_send_sync::<LoxError>();
+
+        let error = Vm::new().eval("print undefined;").unwrap_err();
+        let boxed: Box<dyn std::error::Error + Send + Sync> = Box::new(error);
+        let message = std::thread::spawn(move || boxed.to_string())
+            .join()
+            .unwrap();
+        assert!(message.starts_with("Undefined variable 'undefined'."));
+    }
+
+    #[test]
+    fn values_display_like_print() {
+        let mut vm = Vm::new();
+        assert_eq!(vm.eval("3.0;").unwrap().to_string(), "3.0");
+        assert_eq!(vm.eval("4 / 2;").unwrap().to_string(), "2.0");
+        assert_eq!(vm.eval("3;").unwrap().to_string(), "3");
+        assert_eq!(vm.eval("str(3.0);").unwrap().to_string(), "3.0");
+    }
+
+    #[test]
+    fn collection_storage_counts_towards_the_heap() {
+        let mut vm = Vm::new();
+        vm.eval(
+            "var list = [];
+            var map = {};
+            for (var i = 0; i < 10000; i = i + 1) {
+              append(list, i);
+              map[i] = i;
+            }",
+        )
+        .unwrap();
+        let full = vm.vm.live_bytes();
+
+        vm.eval("list = nil; map = nil;").unwrap();
+        let empty = vm.vm.live_bytes();
+
+        // The list items, map keys and map buckets each take at least a value per entry
+        let values = 3 * 10000 * std::mem::size_of::<value::Value>();
+        assert!(full - empty >= values, "{} bytes freed", full - empty);
+    }
+
+    #[test]
+    fn field_tables_count_towards_the_heap() {
+        let mut vm = Vm::new();
+        vm.eval(
+            "class Point {}
+            var points = [];
+            for (var i = 0; i < 1000; i = i + 1) {
+              var point = Point();
+              point.x = i;
+              append(points, point);
+            }",
+        )
+        .unwrap();
+        let full = vm.vm.live_bytes();
+
+        vm.eval("points = nil;").unwrap();
+        let empty = vm.vm.live_bytes();
+
+        // A table starts out with eight buckets, each at least as big as a value
+        let fields = 1000 * 8 * std::mem::size_of::<value::Value>();
+        assert!(full - empty >= fields, "{} bytes freed", full - empty);
+    }
+}
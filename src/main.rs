@@ -1,20 +1,16 @@
-mod chunk;
-mod common;
-mod compiler;
-mod debug;
-mod object;
-mod scanner;
-mod value;
-mod vm;
-
+use rlox::{LoxError, Vm};
 use std::env;
 use std::fs;
 use std::io;
 use std::io::{ErrorKind, Write};
 use std::process;
-use vm::{InterpretResult, VM};
 
-fn repl(vm: &mut VM) {
+fn report(vm: &mut Vm, error: &LoxError, source: &str) {
+    // There's nowhere left to complain to if stderr itself fails
+    let _ = vm.report(error, source);
+}
+
+fn repl(vm: &mut Vm) {
     loop {
         let mut buffer = String::new();
         print!("> ");
@@ -24,23 +20,24 @@ fn repl(vm: &mut VM) {
                 println!();
                 break;
             }
-            Ok(_) => unsafe {
-                vm.interpret(&buffer);
-            },
+            Ok(_) => {
+                if let Err(error) = vm.eval(&buffer) {
+                    report(vm, &error, &buffer);
+                }
+            }
             Err(error) => eprintln!("error: {}", error),
         }
     }
 }
 
-fn run_file(vm: &mut VM, path: &str) {
+fn run_file(vm: &mut Vm, path: &str) {
     match fs::read_to_string(path) {
-        Ok(contents) => unsafe {
-            match vm.interpret(&contents) {
-                InterpretResult::CompileErr => process::exit(65),
-                InterpretResult::RuntimeErr => process::exit(70),
-                _ => (),
+        Ok(contents) => {
+            if let Err(error) = vm.eval(&contents) {
+                report(vm, &error, &contents);
+                process::exit(error.exit_code());
             }
-        },
+        }
         Err(error) => match error.kind() {
             ErrorKind::NotFound => {
                 eprintln!("File not found \"{}\"", path);
@@ -59,7 +56,7 @@ fn run_file(vm: &mut VM, path: &str) {
 }
 
 fn main() {
-    let mut vm = VM::new();
+    let mut vm = Vm::new();
 
     let args: Vec<String> = env::args().collect();
     match args.len() {
@@ -0,0 +1,272 @@
+use crate::object::{
+    BoundMethodObj, ClassObj, ClosureObj, FunctionObj, InstanceObj, ListObj, MapObj, NativeObj,
+    Obj, StringObj, UpvalueObj,
+};
+use crate::table::{Key, Table};
+use crate::value::Value;
+use std::mem;
+
+const GC_HEAP_GROW_FACTOR: usize = 2;
+const GC_INITIAL_THRESHOLD: usize = 1024 * 1024;
+
+pub struct Heap {
+    // Head of the list of all allocated objects
+    objects: Option<Obj>,
+    bytes_allocated: usize,
+    next_gc: usize,
+    gray_stack: Vec<Obj>,
+}
+
+fn type_name(obj: Obj) -> &'static str {
+    match obj {
+        Obj::StringObj(_) => "string",
+        Obj::FunctionObj(_) => "function",
+        Obj::ClosureObj(_) => "closure",
+        Obj::UpvalueObj(_) => "upvalue",
+        Obj::ClassObj(_) => "class",
+        Obj::InstanceObj(_) => "instance",
+        Obj::BoundMethodObj(_) => "bound method",
+        Obj::NativeObj(_) => "native",
+        Obj::ListObj(_) => "list",
+        Obj::MapObj(_) => "map",
+    }
+}
+
+unsafe fn size_of_obj(obj: Obj) -> usize {
+    match obj {
+        Obj::StringObj(obj) => {
+            let string = &*obj;
+            mem::size_of::<StringObj>() + string.chars.len()
+        }
+        Obj::FunctionObj(_) => mem::size_of::<FunctionObj>(),
+        Obj::ClosureObj(obj) => {
+            let closure = &*obj;
+            mem::size_of::<ClosureObj>()
+                + closure.upvalues.capacity() * mem::size_of::<*mut UpvalueObj>()
+        }
+        Obj::UpvalueObj(_) => mem::size_of::<UpvalueObj>(),
+        // Tables and collections grow after they're allocated, so changes go through `Heap::resize`
+        Obj::ClassObj(obj) => mem::size_of::<ClassObj>() + (*obj).methods.allocated_size(),
+        Obj::InstanceObj(obj) => mem::size_of::<InstanceObj>() + (*obj).fields.allocated_size(),
+        Obj::BoundMethodObj(_) => mem::size_of::<BoundMethodObj>(),
+        Obj::NativeObj(_) => mem::size_of::<NativeObj>(),
+        Obj::ListObj(obj) => {
+            let list = &*obj;
+            mem::size_of::<ListObj>() + list.items.capacity() * mem::size_of::<Value>()
+        }
+        Obj::MapObj(obj) => {
+            let map = &*obj;
+            mem::size_of::<MapObj>()
+                + map.table.allocated_size()
+                + map.keys.capacity() * mem::size_of::<Value>()
+        }
+    }
+}
+
+impl Heap {
+    pub fn new() -> Self {
+        Heap {
+            objects: None,
+            bytes_allocated: 0,
+            next_gc: GC_INITIAL_THRESHOLD,
+            gray_stack: Vec::new(),
+        }
+    }
+
+    #[cfg(test)]
+    pub fn bytes_allocated(&self) -> usize {
+        self.bytes_allocated
+    }
+
+    #[inline]
+    pub fn should_collect(&self) -> bool {
+        cfg!(feature = "debug-stress-gc") || self.bytes_allocated > self.next_gc
+    }
+
+    // Takes ownership of a freshly allocated object
+    pub unsafe fn link(&mut self, obj: Obj) {
+        let size = size_of_obj(obj);
+        self.bytes_allocated += size;
+
+        obj.header().next = self.objects;
+        self.objects = Some(obj);
+
+        if cfg!(feature = "debug-log-gc") {
+            println!(
+                "{:p} allocate {} for {}",
+                obj.header(),
+                size,
+                type_name(obj)
+            );
+        }
+    }
+
+    // Runs `change` on an object whose storage can grow, keeping the count of allocated bytes in
+    // step with it
+    pub unsafe fn resize<R>(&mut self, obj: Obj, change: impl FnOnce() -> R) -> R {
+        let before = size_of_obj(obj);
+        let result = change();
+        self.bytes_allocated = self.bytes_allocated - before + size_of_obj(obj);
+        result
+    }
+
+    pub unsafe fn mark_value(&mut self, value: Value) {
+        if let Value::Obj(obj) = value {
+            self.mark_object(obj);
+        }
+    }
+
+    pub unsafe fn mark_object(&mut self, obj: Obj) {
+        let header = obj.header();
+        if header.is_marked {
+            return;
+        }
+
+        if cfg!(feature = "debug-log-gc") {
+            println!("{:p} mark {}", header, obj);
+        }
+
+        header.is_marked = true;
+        self.gray_stack.push(obj);
+    }
+
+    unsafe fn blacken_object(&mut self, obj: Obj) {
+        if cfg!(feature = "debug-log-gc") {
+            println!("{:p} blacken {}", obj.header(), obj);
+        }
+
+        match obj {
+            Obj::StringObj(_) | Obj::NativeObj(_) => (),
+            Obj::FunctionObj(function) => {
+                if let Some(name) = (*function).name {
+                    self.mark_object(Obj::StringObj(name));
+                }
+                for &constant in (*function).chunk.constants.iter() {
+                    self.mark_value(constant);
+                }
+            }
+            Obj::ClosureObj(closure) => {
+                self.mark_object(Obj::FunctionObj((*closure).function));
+                for &upvalue in (*closure).upvalues.iter() {
+                    self.mark_object(Obj::UpvalueObj(upvalue));
+                }
+            }
+            Obj::UpvalueObj(upvalue) => self.mark_value((*upvalue).closed),
+            Obj::ClassObj(class) => {
+                self.mark_object(Obj::StringObj((*class).name));
+                self.mark_table(&(*class).methods);
+            }
+            Obj::InstanceObj(instance) => {
+                self.mark_object(Obj::ClassObj((*instance).class));
+                self.mark_table(&(*instance).fields);
+            }
+            Obj::BoundMethodObj(bound) => {
+                self.mark_value((*bound).receiver);
+                self.mark_object(Obj::ClosureObj((*bound).method));
+            }
+            Obj::ListObj(list) => {
+                for &item in (*list).items.iter() {
+                    self.mark_value(item);
+                }
+            }
+            // Every key is also in the table
+            Obj::MapObj(map) => self.mark_table(&(*map).table),
+        }
+    }
+
+    pub unsafe fn mark_table<K: Key>(&mut self, table: &Table<K>) {
+        for (key, value) in table.iter() {
+            self.mark_value(key.to_value());
+            self.mark_value(value);
+        }
+    }
+
+    // Runs a full mark-and-sweep collection, with `mark_roots` marking everything the VM can reach.
+    // The interned `strings` are weak references, so unreachable ones are dropped from the table.
+    pub unsafe fn collect<F: FnOnce(&mut Heap)>(&mut self, strings: &mut Table, mark_roots: F) {
+        if cfg!(feature = "debug-log-gc") {
+            println!("-- gc begin");
+        }
+        let before = self.bytes_allocated;
+
+        mark_roots(self);
+        self.trace_references();
+        strings.remove_white();
+        self.sweep();
+
+        self.next_gc = self.bytes_allocated * GC_HEAP_GROW_FACTOR;
+
+        if cfg!(feature = "debug-log-gc") {
+            println!("-- gc end");
+            println!(
+                "   collected {} bytes (from {} to {}) next at {}",
+                before - self.bytes_allocated,
+                before,
+                self.bytes_allocated,
+                self.next_gc
+            );
+        }
+    }
+
+    unsafe fn trace_references(&mut self) {
+        while let Some(obj) = self.gray_stack.pop() {
+            self.blacken_object(obj);
+        }
+    }
+
+    unsafe fn sweep(&mut self) {
+        let mut previous: Option<Obj> = None;
+        let mut object = self.objects;
+
+        while let Some(obj) = object {
+            let header = obj.header();
+            if header.is_marked {
+                header.is_marked = false;
+                previous = object;
+                object = header.next;
+            } else {
+                let unreached = obj;
+                object = header.next;
+                match previous {
+                    Some(previous) => previous.header().next = object,
+                    None => self.objects = object,
+                }
+
+                self.free_object(unreached);
+            }
+        }
+    }
+
+    unsafe fn free_object(&mut self, obj: Obj) {
+        if cfg!(feature = "debug-log-gc") {
+            println!("{:p} free type {}", obj.header(), type_name(obj));
+        }
+
+        self.bytes_allocated -= size_of_obj(obj);
+
+        match obj {
+            Obj::StringObj(obj) => drop(Box::from_raw(obj)),
+            Obj::FunctionObj(obj) => drop(Box::from_raw(obj)),
+            Obj::ClosureObj(obj) => drop(Box::from_raw(obj)),
+            Obj::UpvalueObj(obj) => drop(Box::from_raw(obj)),
+            Obj::ClassObj(obj) => drop(Box::from_raw(obj)),
+            Obj::InstanceObj(obj) => drop(Box::from_raw(obj)),
+            Obj::BoundMethodObj(obj) => drop(Box::from_raw(obj)),
+            Obj::NativeObj(obj) => drop(Box::from_raw(obj)),
+            Obj::ListObj(obj) => drop(Box::from_raw(obj)),
+            Obj::MapObj(obj) => drop(Box::from_raw(obj)),
+        }
+    }
+}
+
+impl Drop for Heap {
+    fn drop(&mut self) {
+        let mut object = self.objects;
+        while let Some(obj) = object {
+            unsafe {
+                object = obj.header().next;
+                self.free_object(obj);
+            }
+        }
+    }
+}
@@ -0,0 +1,160 @@
+use crate::compiler::parse_number;
+use crate::object::{ListObj, MapObj, Obj};
+use crate::value::Value;
+use crate::vm::VM;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+// Seconds since the Unix epoch, for timing scripts
+pub fn clock(_vm: &mut VM, _args: &[Value]) -> Result<Value, String> {
+    let now = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map_err(|_| "System clock is set before the Unix epoch.".to_string())?;
+    Ok(Value::Number(now.as_secs_f64()))
+}
+
+// Converts any value to the string that `print` would show for it
+pub fn str(vm: &mut VM, args: &[Value]) -> Result<Value, String> {
+    match args[0] {
+        Value::Obj(Obj::StringObj(_)) => Ok(args[0]),
+        value => {
+            let string = unsafe { vm.take_string(value.to_string()) };
+            Ok(Value::Obj(Obj::StringObj(string)))
+        }
+    }
+}
+
+// Parses a string the way a number literal is parsed, with an optional leading minus sign
+pub fn num(_vm: &mut VM, args: &[Value]) -> Result<Value, String> {
+    match args[0] {
+        Value::Int(_) | Value::Number(_) => Ok(args[0]),
+        Value::Obj(Obj::StringObj(string)) => {
+            let chars = unsafe { (*string).as_str() };
+            let text = chars.trim();
+            let (negative, digits) = match text.strip_prefix('-') {
+                Some(digits) => (true, digits),
+                None => (false, text),
+            };
+            match (parse_number(digits).ok(), negative) {
+                (Some(Value::Int(value)), true) => Ok(Value::Int(-value)),
+                (Some(Value::Number(value)), true) => Ok(Value::Number(-value)),
+                (Some(value), false) => Ok(value),
+                _ => Err(format!("Can't convert '{}' to a number.", chars)),
+            }
+        }
+        _ => Err("Argument to 'num' must be a number or a string.".to_string()),
+    }
+}
+
+pub fn len(_vm: &mut VM, args: &[Value]) -> Result<Value, String> {
+    let length = match args[0] {
+        Value::Obj(Obj::StringObj(string)) => unsafe { (*string).as_str().chars().count() },
+        Value::Obj(Obj::ListObj(list)) => unsafe { (*list).items.len() },
+        Value::Obj(Obj::MapObj(map)) => unsafe { (*map).keys.len() },
+        _ => return Err("Argument to 'len' must be a string, a list or a map.".to_string()),
+    };
+    Ok(Value::Int(length as i64))
+}
+
+pub fn type_(vm: &mut VM, args: &[Value]) -> Result<Value, String> {
+    let name = match args[0] {
+        Value::Nil => "nil",
+        Value::Bool(_) => "boolean",
+        Value::Int(_) | Value::Number(_) => "number",
+        Value::Obj(obj) => match obj {
+            Obj::StringObj(_) => "string",
+            Obj::ClassObj(_) => "class",
+            Obj::InstanceObj(_) => "instance",
+            Obj::ListObj(_) => "list",
+            Obj::MapObj(_) => "map",
+            Obj::FunctionObj(_)
+            | Obj::ClosureObj(_)
+            | Obj::BoundMethodObj(_)
+            | Obj::NativeObj(_) => "function",
+            Obj::UpvalueObj(_) => unreachable!(),
+        },
+    };
+    let string = unsafe { vm.copy_string(name) };
+    Ok(Value::Obj(Obj::StringObj(string)))
+}
+
+fn list_argument<'a>(name: &str, value: Value) -> Result<&'a mut ListObj, String> {
+    match value {
+        Value::Obj(Obj::ListObj(list)) => Ok(unsafe { &mut *list }),
+        _ => Err(format!("First argument to '{}' must be a list.", name)),
+    }
+}
+
+fn index_argument(name: &str, value: Value) -> Result<i64, String> {
+    match value {
+        Value::Int(index) => Ok(index),
+        _ => Err(format!("Index passed to '{}' must be an integer.", name)),
+    }
+}
+
+pub fn append(vm: &mut VM, args: &[Value]) -> Result<Value, String> {
+    let list = list_argument("append", args[0])?;
+    unsafe { vm.resize(Obj::ListObj(list), || list.items.push(args[1])) };
+    Ok(Value::Nil)
+}
+
+// Removes and returns the last item
+pub fn pop(_vm: &mut VM, args: &[Value]) -> Result<Value, String> {
+    list_argument("pop", args[0])?
+        .items
+        .pop()
+        .ok_or_else(|| "Can't pop from an empty list.".to_string())
+}
+
+// Inserts before `index`, which can also be the length of the list to add to the end
+pub fn insert(vm: &mut VM, args: &[Value]) -> Result<Value, String> {
+    let list = list_argument("insert", args[0])?;
+    let index = index_argument("insert", args[1])?;
+
+    let len = list.items.len() as i64;
+    let index = if index < 0 { index + len } else { index };
+    if !(0..=len).contains(&index) {
+        return Err("Index out of bounds.".to_string());
+    }
+
+    unsafe {
+        vm.resize(Obj::ListObj(list), || {
+            list.items.insert(index as usize, args[2])
+        })
+    };
+    Ok(Value::Nil)
+}
+
+// Removes and returns the item at `index`
+pub fn remove(_vm: &mut VM, args: &[Value]) -> Result<Value, String> {
+    let list = list_argument("remove", args[0])?;
+    let index = index_argument("remove", args[1])?;
+
+    match list.index(index) {
+        Some(index) => Ok(list.items.remove(index)),
+        None => Err("Index out of bounds.".to_string()),
+    }
+}
+
+fn map_argument<'a>(name: &str, value: Value) -> Result<&'a MapObj, String> {
+    match value {
+        Value::Obj(Obj::MapObj(map)) => Ok(unsafe { &*map }),
+        _ => Err(format!("Argument to '{}' must be a map.", name)),
+    }
+}
+
+// The keys of a map as a list, in the order they were added
+pub fn keys(vm: &mut VM, args: &[Value]) -> Result<Value, String> {
+    let keys = map_argument("keys", args[0])?.keys.clone();
+    let list = unsafe { vm.new_list(keys) };
+    Ok(Value::Obj(Obj::ListObj(list)))
+}
+
+// The values of a map as a list, in the same order as `keys`
+pub fn values(vm: &mut VM, args: &[Value]) -> Result<Value, String> {
+    let values = map_argument("values", args[0])?
+        .entries()
+        .map(|(_, value)| value)
+        .collect();
+    let list = unsafe { vm.new_list(values) };
+    Ok(Value::Obj(Obj::ListObj(list)))
+}
@@ -1,32 +1,365 @@
-use std::slice;
-use std::str;
+use crate::chunk::Chunk;
+use crate::table::Table;
+use crate::value::Value;
+use crate::vm::VM;
+use std::fmt;
+use std::ptr;
+
+// Common state of every object allocated on the VM heap
+pub struct ObjHeader {
+    pub is_marked: bool,
+    // The next object in the heap's list of all allocated objects
+    pub next: Option<Obj>,
+}
 
-#[derive(Copy, Clone)]
 pub struct StringObj {
-    pub ptr: *const u8,
-    pub len: usize,
+    pub header: ObjHeader,
+    pub chars: Box<str>,
+    // Cached FNV-1a hash of `chars`
+    pub hash: u32,
+}
+
+pub struct FunctionObj {
+    pub header: ObjHeader,
+    pub arity: usize,
+    pub upvalue_count: usize,
+    pub chunk: Chunk,
+    pub name: Option<*mut StringObj>,
+}
+
+pub struct ClosureObj {
+    pub header: ObjHeader,
+    pub function: *mut FunctionObj,
+    pub upvalues: Vec<*mut UpvalueObj>,
+}
+
+pub struct UpvalueObj {
+    pub header: ObjHeader,
+    // Points into the stack while the upvalue is open, and at `closed` once it's closed
+    pub location: *mut Value,
+    pub closed: Value,
+    // The next open upvalue, sorted by descending stack slot
+    pub next: *mut UpvalueObj,
+}
+
+pub struct ClassObj {
+    pub header: ObjHeader,
+    pub name: *mut StringObj,
+    pub methods: Table,
 }
 
+pub struct InstanceObj {
+    pub header: ObjHeader,
+    pub class: *mut ClassObj,
+    pub fields: Table,
+}
+
+pub struct BoundMethodObj {
+    pub header: ObjHeader,
+    pub receiver: Value,
+    pub method: *mut ClosureObj,
+}
+
+pub struct ListObj {
+    pub header: ObjHeader,
+    pub items: Vec<Value>,
+}
+
+// Keys are kept in the order they were first added, which is the order the map is shown in
+pub struct MapObj {
+    pub header: ObjHeader,
+    pub table: Table<Value>,
+    pub keys: Vec<Value>,
+}
+
+// A function implemented in Rust. Errors are reported as runtime errors at the call site.
+pub type NativeFn = Box<dyn Fn(&mut VM, &[Value]) -> Result<Value, String>>;
+
+pub struct NativeObj {
+    pub header: ObjHeader,
+    pub arity: usize,
+    pub function: NativeFn,
+}
+
+// Strings are interned, so every variant compares by identity
+#[allow(clippy::enum_variant_names)]
 #[derive(Copy, Clone, PartialEq)]
 pub enum Obj {
-    StringObj(StringObj),
+    StringObj(*mut StringObj),
+    FunctionObj(*mut FunctionObj),
+    ClosureObj(*mut ClosureObj),
+    UpvalueObj(*mut UpvalueObj),
+    ClassObj(*mut ClassObj),
+    InstanceObj(*mut InstanceObj),
+    BoundMethodObj(*mut BoundMethodObj),
+    NativeObj(*mut NativeObj),
+    ListObj(*mut ListObj),
+    MapObj(*mut MapObj),
+}
+
+impl ObjHeader {
+    fn new() -> Self {
+        ObjHeader {
+            is_marked: false,
+            next: None,
+        }
+    }
+}
+
+pub fn hash_string(chars: &str) -> u32 {
+    let mut hash: u32 = 2166136261;
+    for &byte in chars.as_bytes() {
+        hash ^= byte as u32;
+        hash = hash.wrapping_mul(16777619);
+    }
+    hash
 }
 
 impl StringObj {
+    pub fn new(chars: Box<str>, hash: u32) -> Self {
+        StringObj {
+            header: ObjHeader::new(),
+            chars,
+            hash,
+        }
+    }
+
     #[inline]
-    pub unsafe fn as_slice(&self) -> &[u8] {
-        slice::from_raw_parts(self.ptr, self.len)
+    pub fn as_str(&self) -> &str {
+        &self.chars
     }
+}
+
+impl FunctionObj {
+    pub fn new() -> Self {
+        FunctionObj {
+            header: ObjHeader::new(),
+            arity: 0,
+            upvalue_count: 0,
+            chunk: Chunk::new(),
+            name: None,
+        }
+    }
+}
+
+impl fmt::Display for FunctionObj {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self.name {
+            Some(name) => unsafe { write!(f, "<fn {}>", (*name).as_str()) },
+            None => write!(f, "<script>"),
+        }
+    }
+}
+
+impl ClosureObj {
+    pub fn new(function: *mut FunctionObj) -> Self {
+        let upvalue_count = unsafe { (*function).upvalue_count };
+        ClosureObj {
+            header: ObjHeader::new(),
+            function,
+            upvalues: Vec::with_capacity(upvalue_count),
+        }
+    }
+}
+
+impl UpvalueObj {
+    pub fn new(slot: *mut Value) -> Self {
+        UpvalueObj {
+            header: ObjHeader::new(),
+            location: slot,
+            closed: Value::Nil,
+            next: ptr::null_mut(),
+        }
+    }
+}
+
+impl ClassObj {
+    pub fn new(name: *mut StringObj) -> Self {
+        ClassObj {
+            header: ObjHeader::new(),
+            name,
+            methods: Table::new(),
+        }
+    }
+}
+
+impl InstanceObj {
+    pub fn new(class: *mut ClassObj) -> Self {
+        InstanceObj {
+            header: ObjHeader::new(),
+            class,
+            fields: Table::new(),
+        }
+    }
+}
+
+impl BoundMethodObj {
+    pub fn new(receiver: Value, method: *mut ClosureObj) -> Self {
+        BoundMethodObj {
+            header: ObjHeader::new(),
+            receiver,
+            method,
+        }
+    }
+}
+
+impl NativeObj {
+    pub fn new(arity: usize, function: NativeFn) -> Self {
+        NativeObj {
+            header: ObjHeader::new(),
+            arity,
+            function,
+        }
+    }
+}
+
+impl ListObj {
+    pub fn new(items: Vec<Value>) -> Self {
+        ListObj {
+            header: ObjHeader::new(),
+            items,
+        }
+    }
+
+    // Negative indices count back from the end of the list
+    pub fn index(&self, index: i64) -> Option<usize> {
+        let len = self.items.len() as i64;
+        let index = if index < 0 { index + len } else { index };
+        (0..len).contains(&index).then_some(index as usize)
+    }
+}
+
+impl MapObj {
+    pub fn new() -> Self {
+        MapObj {
+            header: ObjHeader::new(),
+            table: Table::new(),
+            keys: Vec::new(),
+        }
+    }
+
+    // The key has to be hashable
+    pub fn set(&mut self, key: Value, value: Value) {
+        if self.table.set(key, value) {
+            self.keys.push(key);
+        }
+    }
+
+    pub fn entries(&self) -> impl Iterator<Item = (Value, Value)> + '_ {
+        self.keys
+            .iter()
+            .map(|&key| (key, self.table.get(key).unwrap_or(Value::Nil)))
+    }
+}
+
+// Collections that contain themselves are shown as `[...]` or `{...}` instead of recursing forever
+unsafe fn write_collection(
+    f: &mut fmt::Formatter<'_>,
+    obj: Obj,
+    enclosing: &mut Vec<Obj>,
+) -> fmt::Result {
+    if enclosing.contains(&obj) {
+        return match obj {
+            Obj::ListObj(_) => write!(f, "[...]"),
+            _ => write!(f, "{{...}}"),
+        };
+    }
+
+    enclosing.push(obj);
+    match obj {
+        Obj::ListObj(list) => {
+            write!(f, "[")?;
+            for (i, &item) in (*list).items.iter().enumerate() {
+                if i > 0 {
+                    write!(f, ", ")?;
+                }
+                write_item(f, item, enclosing)?;
+            }
+            write!(f, "]")?;
+        }
+        Obj::MapObj(map) => {
+            write!(f, "{{")?;
+            for (i, (key, value)) in (*map).entries().enumerate() {
+                if i > 0 {
+                    write!(f, ", ")?;
+                }
+                write_item(f, key, enclosing)?;
+                write!(f, ": ")?;
+                write_item(f, value, enclosing)?;
+            }
+            write!(f, "}}")?;
+        }
+        _ => write!(f, "{}", obj)?,
+    }
+    enclosing.pop();
+    Ok(())
+}
+
+unsafe fn write_item(
+    f: &mut fmt::Formatter<'_>,
+    item: Value,
+    enclosing: &mut Vec<Obj>,
+) -> fmt::Result {
+    match item {
+        Value::Obj(obj @ (Obj::ListObj(_) | Obj::MapObj(_))) => write_collection(f, obj, enclosing),
+        Value::Obj(Obj::StringObj(string)) => write_quoted(f, (*string).as_str()),
+        item => write!(f, "{}", item),
+    }
+}
+
+// Strings inside collections are quoted so `["1"]` can be told apart from `[1]`. The escapes
+// are the ones a string literal uses, so the output reads back as the same string.
+fn write_quoted(f: &mut fmt::Formatter<'_>, string: &str) -> fmt::Result {
+    write!(f, "\"")?;
+    let mut chars = string.chars().peekable();
+    while let Some(c) = chars.next() {
+        match c {
+            '"' => write!(f, "\\\"")?,
+            '\\' => write!(f, "\\\\")?,
+            '\n' => write!(f, "\\n")?,
+            '\t' => write!(f, "\\t")?,
+            '$' if chars.peek() == Some(&'{') => write!(f, "\\$")?,
+            c if c.is_control() => write!(f, "\\u{{{:x}}}", c as u32)?,
+            c => write!(f, "{}", c)?,
+        }
+    }
+    write!(f, "\"")
+}
 
+impl Obj {
     #[inline]
-    pub unsafe fn as_str(&self) -> &str {
-        let slice = self.as_slice();
-        str::from_utf8(slice).unwrap()
+    pub unsafe fn header<'a>(self) -> &'a mut ObjHeader {
+        match self {
+            Obj::StringObj(obj) => &mut (*obj).header,
+            Obj::FunctionObj(obj) => &mut (*obj).header,
+            Obj::ClosureObj(obj) => &mut (*obj).header,
+            Obj::UpvalueObj(obj) => &mut (*obj).header,
+            Obj::ClassObj(obj) => &mut (*obj).header,
+            Obj::InstanceObj(obj) => &mut (*obj).header,
+            Obj::BoundMethodObj(obj) => &mut (*obj).header,
+            Obj::NativeObj(obj) => &mut (*obj).header,
+            Obj::ListObj(obj) => &mut (*obj).header,
+            Obj::MapObj(obj) => &mut (*obj).header,
+        }
     }
 }
 
-impl PartialEq for StringObj {
-    fn eq(&self, rhs: &Self) -> bool {
-        unsafe { self.len == rhs.len && self.as_slice() == rhs.as_slice() }
+impl fmt::Display for Obj {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        unsafe {
+            match *self {
+                Obj::StringObj(obj) => write!(f, "{}", (*obj).as_str()),
+                Obj::FunctionObj(obj) => write!(f, "{}", *obj),
+                Obj::ClosureObj(obj) => write!(f, "{}", *(*obj).function),
+                Obj::UpvalueObj(_) => write!(f, "upvalue"),
+                Obj::ClassObj(obj) => write!(f, "{}", (*(*obj).name).as_str()),
+                Obj::InstanceObj(obj) => {
+                    write!(f, "{} instance", (*(*(*obj).class).name).as_str())
+                }
+                Obj::BoundMethodObj(obj) => write!(f, "{}", *(*(*obj).method).function),
+                Obj::NativeObj(_) => write!(f, "<native fn>"),
+                Obj::ListObj(_) | Obj::MapObj(_) => write_collection(f, *self, &mut Vec::new()),
+            }
+        }
     }
 }
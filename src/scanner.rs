@@ -5,23 +5,37 @@ pub enum TokenType {
     RightParen,
     LeftBrace,
     RightBrace,
+    LeftBracket,
+    RightBracket,
     Comma,
     Dot,
     Minus,
     Plus,
     Semicolon,
+    Colon,
     Slash,
     Star,
+    StarStar,
+    Percent,
+    Ampersand,
+    Pipe,
+    Caret,
+    Tilde,
+    TildeSlash,
     Bang,
     BangEqual,
     Equal,
     EqualEqual,
     Greater,
     GreaterEqual,
+    GreaterGreater,
     Less,
     LessEqual,
+    LessLess,
     Identifier,
     String,
+    // A piece of a string literal that ends in `${`
+    Interpolation,
     Number,
     And,
     Class,
@@ -30,6 +44,7 @@ pub enum TokenType {
     For,
     Fun,
     If,
+    In,
     Nil,
     Or,
     Print,
@@ -40,14 +55,23 @@ pub enum TokenType {
     Var,
     While,
     Error,
-    Eof = 39,
+    Eof = 53,
+}
+
+// A region of the source text. `line` and `column` are where it starts, counting from 1.
+#[derive(Copy, Clone, Debug, Default, PartialEq)]
+pub struct Span {
+    pub start: usize,
+    pub len: usize,
+    pub line: usize,
+    pub column: usize,
 }
 
 #[derive(Copy, Clone)]
 pub struct Token<'a> {
     pub token_type: TokenType,
     pub value: &'a str,
-    pub line: usize,
+    pub span: Span,
 }
 
 pub struct Scanner<'a> {
@@ -55,25 +79,42 @@ pub struct Scanner<'a> {
     start: usize,
     current: usize,
     line: usize,
+    // Column of the next character, counted in characters rather than bytes
+    column: usize,
+    // Position of the token being scanned, since strings can span several lines
+    start_line: usize,
+    start_column: usize,
+    // Brace depth inside each `${` being scanned, innermost last
+    interpolations: Vec<usize>,
 }
 
+// Identifiers follow Unicode's XID rules, plus a leading underscore like in C
 #[inline]
-fn is_alpha(c: u8) -> bool {
-    (c >= b'a' && c <= b'z') || (c >= b'A' && c <= b'Z') || c == b'_'
+fn is_identifier_start(c: char) -> bool {
+    c == '_' || unicode_ident::is_xid_start(c)
 }
 
 #[inline]
-fn is_digit(c: u8) -> bool {
-    c >= b'0' && c <= b'9'
+fn is_identifier_continue(c: char) -> bool {
+    unicode_ident::is_xid_continue(c)
+}
+
+#[inline]
+fn is_digit(c: char) -> bool {
+    c.is_ascii_digit()
 }
 
 impl<'a> Scanner<'a> {
     pub fn new(source: &'a str) -> Self {
         Scanner {
-            source: source,
+            source,
             start: 0,
             current: 0,
             line: 1,
+            column: 1,
+            start_line: 1,
+            start_column: 1,
+            interpolations: Vec::new(),
         }
     }
 
@@ -81,13 +122,15 @@ impl<'a> Scanner<'a> {
         self.skip_whitespace();
 
         self.start = self.current;
+        self.start_line = self.line;
+        self.start_column = self.column;
         if self.is_at_end() {
             return self.make_token(TokenType::Eof);
         }
 
         let c = self.advance();
 
-        if is_alpha(c) {
+        if is_identifier_start(c) {
             return self.identifier();
         }
 
@@ -96,50 +139,90 @@ impl<'a> Scanner<'a> {
         }
 
         match c {
-            b'(' => self.make_token(TokenType::LeftParen),
-            b')' => self.make_token(TokenType::RightParen),
-            b'{' => self.make_token(TokenType::LeftBrace),
-            b'}' => self.make_token(TokenType::RightBrace),
-            b';' => self.make_token(TokenType::Semicolon),
-            b',' => self.make_token(TokenType::Comma),
-            b'.' => self.make_token(TokenType::Dot),
-            b'-' => self.make_token(TokenType::Minus),
-            b'+' => self.make_token(TokenType::Plus),
-            b'/' => self.make_token(TokenType::Slash),
-            b'*' => self.make_token(TokenType::Star),
-            b'!' => {
-                let token_type = if self.match_(b'=') {
+            '(' => self.make_token(TokenType::LeftParen),
+            ')' => self.make_token(TokenType::RightParen),
+            '{' => {
+                if let Some(depth) = self.interpolations.last_mut() {
+                    *depth += 1;
+                }
+                self.make_token(TokenType::LeftBrace)
+            }
+            '}' => match self.interpolations.last_mut() {
+                // This closes an interpolated expression, so the string picks up again
+                Some(0) => {
+                    self.interpolations.pop();
+                    self.string()
+                }
+                Some(depth) => {
+                    *depth -= 1;
+                    self.make_token(TokenType::RightBrace)
+                }
+                None => self.make_token(TokenType::RightBrace),
+            },
+            '[' => self.make_token(TokenType::LeftBracket),
+            ']' => self.make_token(TokenType::RightBracket),
+            ';' => self.make_token(TokenType::Semicolon),
+            ':' => self.make_token(TokenType::Colon),
+            ',' => self.make_token(TokenType::Comma),
+            '.' => self.make_token(TokenType::Dot),
+            '-' => self.make_token(TokenType::Minus),
+            '+' => self.make_token(TokenType::Plus),
+            '/' => self.make_token(TokenType::Slash),
+            '*' => {
+                let token_type = if self.match_('*') {
+                    TokenType::StarStar
+                } else {
+                    TokenType::Star
+                };
+                self.make_token(token_type)
+            }
+            '%' => self.make_token(TokenType::Percent),
+            '&' => self.make_token(TokenType::Ampersand),
+            '|' => self.make_token(TokenType::Pipe),
+            '^' => self.make_token(TokenType::Caret),
+            // `~//` is a `~` followed by a comment
+            '~' if self.peek() == '/' && self.peek_next() != '/' => {
+                self.advance();
+                self.make_token(TokenType::TildeSlash)
+            }
+            '~' => self.make_token(TokenType::Tilde),
+            '!' => {
+                let token_type = if self.match_('=') {
                     TokenType::BangEqual
                 } else {
                     TokenType::Bang
                 };
                 self.make_token(token_type)
             }
-            b'=' => {
-                let token_type = if self.match_(b'=') {
+            '=' => {
+                let token_type = if self.match_('=') {
                     TokenType::EqualEqual
                 } else {
                     TokenType::Equal
                 };
                 self.make_token(token_type)
             }
-            b'<' => {
-                let token_type = if self.match_(b'=') {
+            '<' => {
+                let token_type = if self.match_('=') {
                     TokenType::LessEqual
+                } else if self.match_('<') {
+                    TokenType::LessLess
                 } else {
                     TokenType::Less
                 };
                 self.make_token(token_type)
             }
-            b'>' => {
-                let token_type = if self.match_(b'=') {
+            '>' => {
+                let token_type = if self.match_('=') {
                     TokenType::GreaterEqual
+                } else if self.match_('>') {
+                    TokenType::GreaterGreater
                 } else {
                     TokenType::Greater
                 };
                 self.make_token(token_type)
             }
-            b'"' => self.string(),
+            '"' => self.string(),
             _ => self.error_token("Unexpected character."),
         }
     }
@@ -148,20 +231,16 @@ impl<'a> Scanner<'a> {
         loop {
             let c = self.peek();
             match c {
-                b' ' | b'\r' | b'\t' => {
+                ' ' | '\r' | '\t' => {
                     self.advance();
                 }
-                b'\n' => {
-                    self.line += 1;
+                '\n' => {
                     self.advance();
+                    self.new_line();
                 }
-                b'/' => {
-                    if self.peek_next() == b'/' {
-                        while self.peek() != b'\n' && !self.is_at_end() {
-                            self.advance();
-                        }
-                    } else {
-                        return;
+                '/' if self.peek_next() == '/' => {
+                    while self.peek() != '\n' && !self.is_at_end() {
+                        self.advance();
                     }
                 }
                 _ => {
@@ -192,7 +271,7 @@ impl<'a> Scanner<'a> {
                 if self.current - self.start > 1 {
                     match bytes[self.start + 1] {
                         b'a' => self.check_keyword(2, "lse", TokenType::False),
-                        b'o' => self.check_keyword(2, "r", TokenType::False),
+                        b'o' => self.check_keyword(2, "r", TokenType::For),
                         b'u' => self.check_keyword(2, "n", TokenType::Fun),
                         _ => self.make_token(TokenType::Identifier),
                     }
@@ -200,7 +279,17 @@ impl<'a> Scanner<'a> {
                     self.make_token(TokenType::Identifier)
                 }
             }
-            b'i' => self.check_keyword(1, "f", TokenType::If),
+            b'i' => {
+                if self.current - self.start > 1 {
+                    match bytes[self.start + 1] {
+                        b'f' => self.check_keyword(2, "", TokenType::If),
+                        b'n' => self.check_keyword(2, "", TokenType::In),
+                        _ => self.make_token(TokenType::Identifier),
+                    }
+                } else {
+                    self.make_token(TokenType::Identifier)
+                }
+            }
             b'n' => self.check_keyword(1, "il", TokenType::Nil),
             b'o' => self.check_keyword(1, "r", TokenType::Or),
             b'p' => self.check_keyword(1, "rint", TokenType::Print),
@@ -224,94 +313,551 @@ impl<'a> Scanner<'a> {
     }
 
     fn identifier(&mut self) -> Token<'a> {
-        while is_alpha(self.peek()) || is_digit(self.peek()) {
+        while is_identifier_continue(self.peek()) {
             self.advance();
         }
         self.identifier_type()
     }
 
+    // Numbers can have a `0x`, `0o` or `0b` prefix, `_` separators and an exponent. Any letters and
+    // digits that follow are kept in the token, so the compiler can reject it as a whole.
     fn number(&mut self) -> Token<'a> {
-        while is_digit(self.peek()) {
-            self.advance();
-        }
+        let prefixed =
+            self.source[self.start..].starts_with('0') && matches!(self.peek(), 'x' | 'o' | 'b');
+        self.digits(!prefixed);
 
         // Look for a fractional part
-        if self.peek() == b'.' && is_digit(self.peek_next()) {
+        if !prefixed && self.peek() == '.' && is_digit(self.peek_next()) {
             self.advance();
-            while is_digit(self.peek()) {
-                self.advance();
-            }
+            self.digits(true);
         }
 
         self.make_token(TokenType::Number)
     }
 
+    fn digits(&mut self, decimal: bool) {
+        while is_identifier_continue(self.peek()) {
+            let c = self.advance();
+            // The sign of an exponent like `1e-9`
+            if decimal
+                && matches!(c, 'e' | 'E')
+                && matches!(self.peek(), '+' | '-')
+                && is_digit(self.peek_next())
+            {
+                self.advance();
+            }
+        }
+    }
+
+    // Scans up to the closing quote, or up to the next `${` of an interpolated string
     fn string(&mut self) -> Token<'a> {
-        while self.peek() != b'"' && !self.is_at_end() {
-            if self.peek() == b'\n' {
-                self.line += 1;
+        let mut invalid_escape = None;
+
+        while self.peek() != '"' && !self.is_at_end() {
+            match self.advance() {
+                '\n' => self.new_line(),
+                '\\' => match escape(&self.source[self.current..]) {
+                    // Escape sequences are all ASCII, so bytes and columns line up
+                    Ok((_, len)) => {
+                        self.current += len;
+                        self.column += len;
+                    }
+                    Err(message) => {
+                        invalid_escape = invalid_escape.or(Some((self.escape_span(), message)))
+                    }
+                },
+                '$' if self.peek() == '{' => {
+                    self.advance();
+                    self.interpolations.push(0);
+                    return self.finish_string(TokenType::Interpolation, invalid_escape);
+                }
+                _ => (),
             }
-            self.advance();
         }
 
         if self.is_at_end() {
             self.error_token("Unterminated string.")
         } else {
             self.advance(); // the closing quote
-            self.make_token(TokenType::String)
+            self.finish_string(TokenType::String, invalid_escape)
         }
     }
 
-    fn peek(&self) -> u8 {
-        if self.is_at_end() {
-            b'\0'
-        } else {
-            self.source.as_bytes()[self.current]
+    fn finish_string(
+        &self,
+        token_type: TokenType,
+        invalid_escape: Option<(Span, &'static str)>,
+    ) -> Token<'a> {
+        match invalid_escape {
+            Some((span, message)) => Token {
+                token_type: TokenType::Error,
+                value: message,
+                span,
+            },
+            None => self.make_token(token_type),
         }
     }
 
-    fn peek_next(&self) -> u8 {
-        if self.is_at_end() {
-            b'\0'
-        } else {
-            self.source.as_bytes()[self.current + 1]
+    // The backslash just consumed and the character after it
+    fn escape_span(&self) -> Span {
+        let start = self.current - 1;
+        Span {
+            start,
+            len: 1 + self.peek().len_utf8(),
+            line: self.line,
+            column: self.column - 1,
         }
     }
 
-    fn match_(&mut self, expected: u8) -> bool {
-        if self.is_at_end() || self.source.as_bytes()[self.current] != expected {
+    // Returns '\0' at the end of the source
+    fn peek(&self) -> char {
+        self.source[self.current..].chars().next().unwrap_or('\0')
+    }
+
+    fn peek_next(&self) -> char {
+        self.source[self.current..].chars().nth(1).unwrap_or('\0')
+    }
+
+    fn match_(&mut self, expected: char) -> bool {
+        if self.is_at_end() || self.peek() != expected {
             false
         } else {
-            self.current += 1;
+            self.current += expected.len_utf8();
+            self.column += 1;
             true
         }
     }
 
-    fn advance(&mut self) -> u8 {
-        // Assume ASCII characters
-        self.current += 1;
-        self.source.as_bytes()[self.current - 1]
+    fn advance(&mut self) -> char {
+        let c = self.peek();
+        self.current += c.len_utf8();
+        self.column += 1;
+        c
+    }
+
+    // Called after consuming a newline
+    fn new_line(&mut self) {
+        self.line += 1;
+        self.column = 1;
+    }
+
+    fn span(&self) -> Span {
+        Span {
+            start: self.start,
+            len: self.current - self.start,
+            line: self.start_line,
+            column: self.start_column,
+        }
     }
 
     fn make_token(&self, token_type: TokenType) -> Token<'a> {
         Token {
-            token_type: token_type,
+            token_type,
             value: &self.source[self.start..self.current],
-            line: self.line,
+            span: self.span(),
         }
     }
 
+    // The span still covers the offending source text, while `value` holds the message
     fn error_token(&self, message: &'static str) -> Token<'a> {
         Token {
             token_type: TokenType::Error,
-            value: &message,
-            line: self.line,
+            value: message,
+            span: self.span(),
         }
     }
 
     #[inline]
     fn is_at_end(&self) -> bool {
-        // Assume ASCII characters
         self.current == self.source.len()
     }
 }
+
+// Decodes the escape sequence at the start of `text`, which follows a backslash.
+// Returns the character and the number of bytes of `text` it takes up.
+fn escape(text: &str) -> Result<(char, usize), &'static str> {
+    const INVALID: &str = "Invalid escape sequence.";
+    let c = match text.chars().next().ok_or(INVALID)? {
+        'n' => '\n',
+        't' => '\t',
+        '"' => '"',
+        '\\' => '\\',
+        '$' => '$',
+        'u' => {
+            let digits = text.strip_prefix("u{").ok_or(INVALID)?;
+            let end = digits.find('}').ok_or("Unterminated unicode escape.")?;
+            let digits = &digits[..end];
+            if digits.is_empty()
+                || digits.len() > 6
+                || !digits.chars().all(|c| c.is_ascii_hexdigit())
+            {
+                return Err(INVALID);
+            }
+            let c = u32::from_str_radix(digits, 16)
+                .ok()
+                .and_then(char::from_u32);
+            return Ok((c.ok_or(INVALID)?, digits.len() + 3));
+        }
+        _ => return Err(INVALID),
+    };
+    Ok((c, 1))
+}
+
+// Replaces the escape sequences in the text of a string token, which the scanner has already checked
+pub fn unescape(text: &str) -> String {
+    let mut result = String::with_capacity(text.len());
+    let mut rest = text;
+    while let Some(i) = rest.find('\\') {
+        result.push_str(&rest[..i]);
+        let (c, len) = escape(&rest[i + 1..]).expect("escape was checked by the scanner");
+        result.push(c);
+        rest = &rest[i + 1 + len..];
+    }
+    result.push_str(rest);
+    result
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const KEYWORDS: [(&str, TokenType); 17] = [
+        ("and", TokenType::And),
+        ("class", TokenType::Class),
+        ("else", TokenType::Else),
+        ("false", TokenType::False),
+        ("for", TokenType::For),
+        ("fun", TokenType::Fun),
+        ("if", TokenType::If),
+        ("in", TokenType::In),
+        ("nil", TokenType::Nil),
+        ("or", TokenType::Or),
+        ("print", TokenType::Print),
+        ("return", TokenType::Return),
+        ("super", TokenType::Super),
+        ("this", TokenType::This),
+        ("true", TokenType::True),
+        ("var", TokenType::Var),
+        ("while", TokenType::While),
+    ];
+
+    fn scan(source: &str) -> Vec<Token<'_>> {
+        let mut scanner = Scanner::new(source);
+        let mut tokens = Vec::new();
+        loop {
+            let token = scanner.scan_token();
+            tokens.push(token);
+            if token.token_type == TokenType::Eof {
+                return tokens;
+            }
+        }
+    }
+
+    fn types(source: &str) -> Vec<TokenType> {
+        scan(source).iter().map(|token| token.token_type).collect()
+    }
+
+    fn single(source: &str) -> Token<'_> {
+        let tokens = scan(source);
+        assert_eq!(tokens.len(), 2, "expected one token in {:?}", source);
+        tokens[0]
+    }
+
+    #[test]
+    fn keywords() {
+        for (keyword, token_type) in KEYWORDS {
+            let token = single(keyword);
+            assert!(token.token_type == token_type, "{}", keyword);
+            assert_eq!(token.value, keyword);
+        }
+    }
+
+    #[test]
+    fn keyword_prefixes_and_extensions_are_identifiers() {
+        for (keyword, _) in KEYWORDS {
+            for end in 1..keyword.len() {
+                let prefix = &keyword[..end];
+                if KEYWORDS.iter().any(|(other, _)| *other == prefix) {
+                    continue;
+                }
+                assert!(
+                    single(prefix).token_type == TokenType::Identifier,
+                    "{}",
+                    prefix
+                );
+            }
+
+            for extended in [format!("{}s", keyword), format!("{}_", keyword)] {
+                let token = single(&extended);
+                assert!(token.token_type == TokenType::Identifier, "{}", extended);
+                assert_eq!(token.value, extended);
+            }
+
+            let capitalized = keyword[..1].to_uppercase() + &keyword[1..];
+            assert!(single(&capitalized).token_type == TokenType::Identifier);
+        }
+    }
+
+    #[test]
+    fn every_letter_after_a_keyword_branch() {
+        // Covers each arm of the keyword trie, including letters that lead nowhere
+        for first in 'a'..='z' {
+            for second in 'a'..='z' {
+                let name = format!("{}{}", first, second);
+                let expected = match name.as_str() {
+                    "if" => TokenType::If,
+                    "in" => TokenType::In,
+                    "or" => TokenType::Or,
+                    _ => TokenType::Identifier,
+                };
+                assert!(single(&name).token_type == expected, "{}", name);
+            }
+        }
+    }
+
+    #[test]
+    fn punctuation() {
+        assert!(
+            types("(){}[];:,.-+/* ** % & | ^ ~ ~/ ! != = == > >= >> < <= <<")
+                == [
+                    TokenType::LeftParen,
+                    TokenType::RightParen,
+                    TokenType::LeftBrace,
+                    TokenType::RightBrace,
+                    TokenType::LeftBracket,
+                    TokenType::RightBracket,
+                    TokenType::Semicolon,
+                    TokenType::Colon,
+                    TokenType::Comma,
+                    TokenType::Dot,
+                    TokenType::Minus,
+                    TokenType::Plus,
+                    TokenType::Slash,
+                    TokenType::Star,
+                    TokenType::StarStar,
+                    TokenType::Percent,
+                    TokenType::Ampersand,
+                    TokenType::Pipe,
+                    TokenType::Caret,
+                    TokenType::Tilde,
+                    TokenType::TildeSlash,
+                    TokenType::Bang,
+                    TokenType::BangEqual,
+                    TokenType::Equal,
+                    TokenType::EqualEqual,
+                    TokenType::Greater,
+                    TokenType::GreaterEqual,
+                    TokenType::GreaterGreater,
+                    TokenType::Less,
+                    TokenType::LessEqual,
+                    TokenType::LessLess,
+                    TokenType::Eof,
+                ]
+        );
+    }
+
+    #[test]
+    fn numbers() {
+        for number in [
+            "0",
+            "123",
+            "1.5",
+            "0.25",
+            "0xff",
+            "0o17",
+            "0b1010",
+            "1_000_000",
+            "1e9",
+            "1e-9",
+            "2.5E+3",
+            "0x1_F",
+            "0xe",
+            "0b102",
+            "12abc",
+            "1__0",
+        ] {
+            let token = single(number);
+            assert!(token.token_type == TokenType::Number);
+            assert_eq!(token.value, number);
+        }
+
+        // A trailing or leading dot isn't part of the number
+        assert!(types("1.") == [TokenType::Number, TokenType::Dot, TokenType::Eof]);
+        assert!(types(".5") == [TokenType::Dot, TokenType::Number, TokenType::Eof]);
+
+        // Only decimal exponents take a sign
+        assert!(
+            types("0xe-1")
+                == [
+                    TokenType::Number,
+                    TokenType::Minus,
+                    TokenType::Number,
+                    TokenType::Eof
+                ]
+        );
+        assert!(
+            types("1e-x")
+                == [
+                    TokenType::Number,
+                    TokenType::Minus,
+                    TokenType::Identifier,
+                    TokenType::Eof
+                ]
+        );
+    }
+
+    #[test]
+    fn unicode_identifiers() {
+        for name in [
+            "café", "ñandú", "变量", "Ωmega", "_private", "x1", "naïve_2",
+        ] {
+            let token = single(name);
+            assert!(token.token_type == TokenType::Identifier, "{}", name);
+            assert_eq!(token.value, name);
+        }
+
+        // Digits from other scripts can continue an identifier but not start one
+        assert_eq!(single("x٣").value, "x٣");
+        assert!(types("٣")[0] == TokenType::Error);
+    }
+
+    #[test]
+    fn unicode_strings() {
+        for string in ["\"héllo\"", "\"日本語\"", "\"☃ and 🦀\"", "\"\""] {
+            let token = single(string);
+            assert!(token.token_type == TokenType::String);
+            assert_eq!(token.value, string);
+        }
+    }
+
+    #[test]
+    fn escapes() {
+        let token = single(r#""\n\t\"\\\$\u{e9}\u{1F980}""#);
+        assert!(token.token_type == TokenType::String);
+        assert_eq!(
+            unescape(&token.value[1..token.value.len() - 1]),
+            "\n\t\"\\$é🦀"
+        );
+
+        for source in [
+            r#""\q""#,
+            r#""\u{}""#,
+            r#""\u{110000}""#,
+            r#""\u{1234567}""#,
+            r#""\u41""#,
+        ] {
+            let token = single(source);
+            assert!(token.token_type == TokenType::Error, "{}", source);
+            assert_eq!(token.value, "Invalid escape sequence.");
+            assert_eq!((token.span.start, token.span.len), (1, 2));
+        }
+
+        let token = single(r#""\u{41""#);
+        assert!(token.token_type == TokenType::Error);
+        assert_eq!(token.value, "Unterminated unicode escape.");
+        assert_eq!((token.span.start, token.span.len), (1, 2));
+
+        // The string itself is unterminated too, which takes precedence
+        let token = single(r#""\u{41"#);
+        assert!(token.token_type == TokenType::Error);
+        assert_eq!(token.value, "Unterminated string.");
+    }
+
+    #[test]
+    fn interpolation() {
+        let tokens = scan(r#""a ${b + "c ${d}"} e" f"#);
+        let tokens: Vec<(TokenType, &str)> = tokens
+            .iter()
+            .map(|token| (token.token_type, token.value))
+            .collect();
+        assert!(
+            tokens
+                == [
+                    (TokenType::Interpolation, "\"a ${"),
+                    (TokenType::Identifier, "b"),
+                    (TokenType::Plus, "+"),
+                    (TokenType::Interpolation, "\"c ${"),
+                    (TokenType::Identifier, "d"),
+                    (TokenType::String, "}\""),
+                    (TokenType::String, "} e\""),
+                    (TokenType::Identifier, "f"),
+                    (TokenType::Eof, ""),
+                ]
+        );
+
+        // Braces inside the expression don't close it
+        assert!(
+            types(r#""${{}}""#)
+                == [
+                    TokenType::Interpolation,
+                    TokenType::LeftBrace,
+                    TokenType::RightBrace,
+                    TokenType::String,
+                    TokenType::Eof,
+                ]
+        );
+    }
+
+    #[test]
+    fn unexpected_characters() {
+        for source in ["@", "#", "☃", "🦀", "`"] {
+            let token = single(source);
+            assert!(token.token_type == TokenType::Error);
+            assert_eq!(token.value, "Unexpected character.");
+            assert_eq!(token.span.len, source.len());
+        }
+    }
+
+    #[test]
+    fn unterminated_string() {
+        let token = single("\"abc\ndef");
+        assert!(token.token_type == TokenType::Error);
+        assert_eq!(token.value, "Unterminated string.");
+        assert_eq!(token.span.line, 1);
+    }
+
+    #[test]
+    fn comments_and_whitespace() {
+        assert!(types("// comment\n\t \r\n// another") == [TokenType::Eof]);
+        assert!(
+            types("a // b\nc") == [TokenType::Identifier, TokenType::Identifier, TokenType::Eof]
+        );
+        assert!(types("// ünïcödé ☃\nx") == [TokenType::Identifier, TokenType::Eof]);
+        assert!(types("~// comment") == [TokenType::Tilde, TokenType::Eof]);
+    }
+
+    #[test]
+    fn spans() {
+        let tokens = scan("var café = \"☃\";\n  print café;");
+        let spans: Vec<(&str, usize, usize)> = tokens
+            .iter()
+            .map(|token| (token.value, token.span.line, token.span.column))
+            .collect();
+        assert_eq!(
+            spans,
+            [
+                ("var", 1, 1),
+                ("café", 1, 5),
+                ("=", 1, 10),
+                ("\"☃\"", 1, 12),
+                (";", 1, 15),
+                ("print", 2, 3),
+                ("café", 2, 9),
+                (";", 2, 13),
+                ("", 2, 14),
+            ]
+        );
+
+        // Multi-line strings are reported where they start
+        let tokens = scan("\"a\nb\" x");
+        assert_eq!(tokens[0].span.line, 1);
+        assert_eq!((tokens[1].span.line, tokens[1].span.column), (2, 4));
+
+        // Invalid escapes point at the backslash, counting earlier characters rather than bytes
+        let token = single("\"\\u{e9}☃\n\\q\"");
+        assert!(token.token_type == TokenType::Error);
+        assert_eq!((token.span.line, token.span.column), (2, 1));
+        let token = single("\"☃\\q\"");
+        assert_eq!((token.span.line, token.span.column), (1, 3));
+    }
+}
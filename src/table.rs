@@ -0,0 +1,230 @@
+use crate::object::{Obj, StringObj};
+use crate::value::{float_as_int, Value};
+use std::mem;
+
+const TABLE_MAX_LOAD: f64 = 0.75;
+
+// Something a table can be keyed by. Keys that compare equal must have the same hash.
+pub trait Key: Copy + PartialEq {
+    fn hash(self) -> u32;
+
+    // The key as a value, so the collector can mark it
+    fn to_value(self) -> Value;
+}
+
+impl Key for *mut StringObj {
+    #[inline]
+    fn hash(self) -> u32 {
+        unsafe { (*self).hash }
+    }
+
+    fn to_value(self) -> Value {
+        Value::Obj(Obj::StringObj(self))
+    }
+}
+
+// Only hashable values are used as keys, but other objects still hash by identity
+impl Key for Value {
+    fn hash(self) -> u32 {
+        match self {
+            Value::Nil => 0,
+            Value::Bool(value) => 1 + value as u32,
+            Value::Int(value) => hash_int(value),
+            // A float holding an integer equals that integer, so it has to hash the same way
+            Value::Number(value) => match float_as_int(value) {
+                Some(value) => hash_int(value),
+                None => hash_int(value.to_bits() as i64),
+            },
+            Value::Obj(Obj::StringObj(string)) => unsafe { (*string).hash },
+            Value::Obj(obj) => hash_int(unsafe { obj.header() } as *const _ as i64),
+        }
+    }
+
+    fn to_value(self) -> Value {
+        self
+    }
+}
+
+// Mixes all 64 bits into the 32 used for hashing
+fn hash_int(value: i64) -> u32 {
+    let value = value as u64;
+    ((value ^ (value >> 32)).wrapping_mul(0x9e37_79b9_7f4a_7c15) >> 32) as u32
+}
+
+#[derive(Copy, Clone)]
+struct Entry<K> {
+    // `None` marks either an empty bucket or, if `value` is true, a tombstone
+    key: Option<K>,
+    value: Value,
+}
+
+// An open addressing hash table with linear probing. Most tables are keyed by interned strings,
+// but maps use any hashable value.
+pub struct Table<K = *mut StringObj> {
+    // Number of live entries plus tombstones
+    count: usize,
+    entries: Vec<Entry<K>>,
+}
+
+impl<K> Entry<K> {
+    const EMPTY: Entry<K> = Entry {
+        key: None,
+        value: Value::Nil,
+    };
+
+    const TOMBSTONE: Entry<K> = Entry {
+        key: None,
+        value: Value::Bool(true),
+    };
+}
+
+impl<K: Key> Table<K> {
+    pub fn new() -> Self {
+        Table {
+            count: 0,
+            entries: Vec::new(),
+        }
+    }
+
+    pub fn get(&self, key: K) -> Option<Value> {
+        if self.count == 0 {
+            return None;
+        }
+
+        let entry = &self.entries[find_entry(&self.entries, key)];
+        entry.key.map(|_| entry.value)
+    }
+
+    // Returns true if `key` wasn't in the table before
+    pub fn set(&mut self, key: K, value: Value) -> bool {
+        if (self.count + 1) as f64 > self.entries.len() as f64 * TABLE_MAX_LOAD {
+            let capacity = if self.entries.len() < 8 {
+                8
+            } else {
+                self.entries.len() * 2
+            };
+            self.adjust_capacity(capacity);
+        }
+
+        let index = find_entry(&self.entries, key);
+        let entry = &mut self.entries[index];
+        let is_new_key = entry.key.is_none();
+        if is_new_key && matches!(entry.value, Value::Nil) {
+            self.count += 1;
+        }
+
+        entry.key = Some(key);
+        entry.value = value;
+        is_new_key
+    }
+
+    pub fn delete(&mut self, key: K) -> bool {
+        if self.count == 0 {
+            return false;
+        }
+
+        let index = find_entry(&self.entries, key);
+        if self.entries[index].key.is_none() {
+            return false;
+        }
+
+        // Leave a tombstone so probe sequences going through this bucket aren't broken
+        self.entries[index] = Entry::TOMBSTONE;
+        true
+    }
+
+    pub fn add_all(&self, to: &mut Table<K>) {
+        for (key, value) in self.iter() {
+            to.set(key, value);
+        }
+    }
+
+    // The bytes taken up by the buckets, for the collector's accounting
+    pub fn allocated_size(&self) -> usize {
+        self.entries.capacity() * mem::size_of::<Entry<K>>()
+    }
+
+    pub fn iter(&self) -> impl Iterator<Item = (K, Value)> + '_ {
+        self.entries
+            .iter()
+            .filter_map(|entry| entry.key.map(|key| (key, entry.value)))
+    }
+
+    fn adjust_capacity(&mut self, capacity: usize) {
+        let mut entries = vec![Entry::EMPTY; capacity];
+
+        // Tombstones aren't copied over, so they no longer count towards the load
+        self.count = 0;
+        for entry in self.entries.iter() {
+            if let Some(key) = entry.key {
+                let index = find_entry(&entries, key);
+                entries[index] = *entry;
+                self.count += 1;
+            }
+        }
+
+        self.entries = entries;
+    }
+}
+
+impl Table {
+    pub fn find_string(&self, chars: &str, hash: u32) -> Option<*mut StringObj> {
+        if self.count == 0 {
+            return None;
+        }
+
+        let capacity = self.entries.len();
+        let mut index = hash as usize % capacity;
+        loop {
+            let entry = &self.entries[index];
+            match entry.key {
+                // Stop if we find an empty non-tombstone entry
+                None if matches!(entry.value, Value::Nil) => return None,
+                None => (),
+                Some(key) => unsafe {
+                    if (*key).hash == hash && (*key).as_str() == chars {
+                        return Some(key);
+                    }
+                },
+            }
+
+            index = (index + 1) % capacity;
+        }
+    }
+
+    // Deletes every entry whose key wasn't marked by the collector
+    pub unsafe fn remove_white(&mut self) {
+        for index in 0..self.entries.len() {
+            if let Some(key) = self.entries[index].key {
+                if !(*key).header.is_marked {
+                    self.entries[index] = Entry::TOMBSTONE;
+                }
+            }
+        }
+    }
+}
+
+fn find_entry<K: Key>(entries: &[Entry<K>], key: K) -> usize {
+    let capacity = entries.len();
+    let mut index = key.hash() as usize % capacity;
+    let mut tombstone = None;
+
+    loop {
+        let entry = &entries[index];
+        match entry.key {
+            None if matches!(entry.value, Value::Nil) => {
+                // Empty entry, so reuse any tombstone we passed on the way
+                return tombstone.unwrap_or(index);
+            }
+            None => {
+                if tombstone.is_none() {
+                    tombstone = Some(index);
+                }
+            }
+            Some(entry_key) if entry_key == key => return index,
+            Some(_) => (),
+        }
+
+        index = (index + 1) % capacity;
+    }
+}
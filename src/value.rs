@@ -1,30 +1,77 @@
-use crate::object::{Obj, StringObj};
+use crate::object::Obj;
 use std::fmt;
 
-#[derive(Copy, Clone, PartialEq)]
+#[derive(Copy, Clone)]
 pub enum Value {
     Nil,
     Bool(bool),
+    Int(i64),
+    // A floating point number
     Number(f64),
     Obj(Obj),
 }
 
 impl Value {
+    // Integers are promoted to floats when they meet one in arithmetic
     #[inline]
-    pub fn string(ptr: *const u8, len: usize) -> Value {
-        Value::Obj(Obj::StringObj(StringObj { ptr: ptr, len: len }))
+    pub fn as_float(self) -> Option<f64> {
+        match self {
+            Value::Int(value) => Some(value as f64),
+            Value::Number(value) => Some(value),
+            _ => None,
+        }
+    }
+
+    // Whether the value can be a map key. NaN can't, since it isn't equal to itself and so could
+    // never be looked up again.
+    pub fn is_hashable(self) -> bool {
+        match self {
+            Value::Nil | Value::Bool(_) | Value::Int(_) | Value::Obj(Obj::StringObj(_)) => true,
+            Value::Number(value) => !value.is_nan(),
+            Value::Obj(_) => false,
+        }
+    }
+}
+
+// The integer a float holds exactly, if any
+pub fn float_as_int(value: f64) -> Option<i64> {
+    // 2^63 itself isn't a valid i64, so the upper bound is exclusive
+    let in_range = value >= i64::MIN as f64 && value < i64::MAX as f64;
+    (value.fract() == 0.0 && in_range).then_some(value as i64)
+}
+
+// An integer and a float are equal when they're the same number, so `1 == 1.0`. The comparison is
+// exact: the integer isn't rounded to a float, which would make large integers equal their neighbours.
+impl PartialEq for Value {
+    fn eq(&self, other: &Self) -> bool {
+        match (*self, *other) {
+            (Value::Nil, Value::Nil) => true,
+            (Value::Bool(a), Value::Bool(b)) => a == b,
+            (Value::Int(a), Value::Int(b)) => a == b,
+            (Value::Number(a), Value::Number(b)) => a == b,
+            (Value::Int(a), Value::Number(b)) | (Value::Number(b), Value::Int(a)) => {
+                float_as_int(b) == Some(a)
+            }
+            (Value::Obj(a), Value::Obj(b)) => a == b,
+            _ => false,
+        }
     }
 }
 
+// Floats always show a fraction or exponent, so `3.0` can't be mistaken for `3`. Shared with the
+// embedding API so values look the same whether they're printed or returned.
+pub fn fmt_float(value: f64, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+    write!(f, "{:?}", value)
+}
+
 impl fmt::Display for Value {
     fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
         match self {
             Value::Nil => write!(f, "nil"),
             Value::Bool(value) => write!(f, "{}", value),
-            Value::Number(value) => write!(f, "{}", value),
-            Value::Obj(value) => match value {
-                Obj::StringObj(obj) => unsafe { write!(f, "{}", obj.as_str()) },
-            },
+            Value::Int(value) => write!(f, "{}", value),
+            Value::Number(value) => fmt_float(*value, f),
+            Value::Obj(value) => write!(f, "{}", value),
         }
     }
 }
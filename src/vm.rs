@@ -1,45 +1,199 @@
-use crate::chunk::{Chunk, OpCode};
+use crate::chunk::OpCode;
 use crate::compiler::Parser;
 use crate::debug::disassemble_instruction;
-use crate::object::{Obj, StringObj};
+use crate::diagnostic::{render_snippet, Diagnostic};
+use crate::memory::Heap;
+use crate::native;
+use crate::object::{
+    hash_string, BoundMethodObj, ClassObj, ClosureObj, FunctionObj, InstanceObj, ListObj, MapObj,
+    NativeObj, Obj, StringObj, UpvalueObj,
+};
+use crate::scanner::Span;
+use crate::table::Table;
 use crate::value::Value;
-use std::ptr;
+use std::fmt;
+use std::io::{self, Write};
+use std::sync::Arc;
+use std::{ptr, slice};
 
-const STACK_MAX: usize = 256;
+const FRAMES_MAX: usize = 64;
+const STACK_MAX: usize = FRAMES_MAX * (u8::MAX as usize + 1);
+// No instruction grows the stack by more than this, so checking for this much room before each
+// one keeps every push in bounds
+const STACK_SLACK: usize = 8;
 
-pub struct VM {
-    chunk: Chunk,
+struct CallFrame {
+    closure: *mut ClosureObj,
     ip: *const u8,
-    stack: [Value; STACK_MAX],
+    // The first stack slot this function can use
+    slots: *mut Value,
+}
+
+pub struct VM {
+    frames: Vec<CallFrame>,
+    stack: Box<[Value]>,
     stack_top: *mut Value,
-    all_strings: Vec<String>,
+    globals: Table,
+    // Interned strings; every string object is unique by content
+    strings: Table,
+    init_string: *mut StringObj,
+    open_upvalues: *mut UpvalueObj,
+    // Functions that are still being compiled
+    compiler_roots: Vec<*mut FunctionObj>,
+    heap: Heap,
+    // Where `print` writes to
+    output: Box<dyn Write>,
 }
 
-#[repr(u8)]
 pub enum InterpretResult {
-    Ok,
-    CompileErr,
-    RuntimeErr,
+    // The value the script or function returned
+    Ok(Value),
+    CompileErr(Vec<Diagnostic>),
+    RuntimeErr(Box<RuntimeError>),
+}
+
+// One call that was active when a runtime error happened
+#[derive(Debug)]
+pub struct TraceFrame {
+    // `None` for the top-level script
+    pub function: Option<String>,
+    pub line: usize,
+}
+
+#[derive(Debug)]
+pub struct RuntimeError {
+    pub diagnostic: Diagnostic,
+    // Innermost call first
+    pub trace: Vec<TraceFrame>,
+    // The source the diagnostic's span points into, which may be from an earlier `interpret`
+    pub source: Arc<str>,
+}
+
+impl fmt::Display for TraceFrame {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match &self.function {
+            Some(name) => write!(f, "[line {}] in {}()", self.line, name),
+            None => write!(f, "[line {}] in script", self.line),
+        }
+    }
+}
+
+impl fmt::Display for RuntimeError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}", self.diagnostic.message)?;
+        for frame in self.trace.iter() {
+            write!(f, "\n{}", frame)?;
+        }
+        Ok(())
+    }
 }
 
+impl RuntimeError {
+    // Writes the message, the offending source line and the stack trace
+    pub fn render(&self, out: &mut dyn Write, color: bool) -> io::Result<()> {
+        writeln!(out, "{}", self.diagnostic.message)?;
+        render_snippet(out, &self.source, self.diagnostic.span, color)?;
+
+        for frame in self.trace.iter() {
+            writeln!(out, "{}", frame)?;
+        }
+        Ok(())
+    }
+}
+
+// Applies `$operator` to the two numbers on top of the stack. Two integers go through `$checked`
+// so that overflow is an error rather than wrapping around; otherwise both are promoted to floats.
+// Operators with no single float equivalent pass a function of the two floats instead.
 macro_rules! binary_op {
-    ($parser: ident, $value_type: path, $operator: tt) => {
+    ($vm: ident, $operator: tt, $checked: ident) => {
+        binary_op!($vm, $checked, |a: f64, b: f64| a $operator b)
+    };
+    ($vm: ident, $checked: ident, $float: expr) => {
         {
-            let b = $parser.peek(0);
-            let a = $parser.peek(1);
+            let b = $vm.peek(0);
+            let a = $vm.peek(1);
 
-            if let (Value::Number(a), Value::Number(b)) = (a, b) {
-                $parser.pop();
-                $parser.pop();
-                $parser.push($value_type(a $operator b));
-            } else {
-                $parser.runtime_error("Operands must be numbers.");
-                break InterpretResult::RuntimeErr
-            }
+            let result = match (a, b) {
+                (Value::Int(a), Value::Int(b)) => match a.$checked(b) {
+                    Some(result) => Value::Int(result),
+                    None => {
+                        // Only division and remainder can fail because of a zero
+                        let message = if b == 0 { "Division by zero." } else { "Integer overflow." };
+                        break InterpretResult::RuntimeErr($vm.runtime_error(message))
+                    }
+                },
+                _ => match (a.as_float(), b.as_float()) {
+                    (Some(a), Some(b)) => Value::Number(($float)(a, b)),
+                    _ => break InterpretResult::RuntimeErr($vm.runtime_error("Operands must be numbers.")),
+                },
+            };
+
+            $vm.pop();
+            $vm.pop();
+            $vm.push(result);
+        }
+    };
+}
+
+// Like `binary_op!`, but for operators that compare the numbers
+macro_rules! comparison_op {
+    ($vm: ident, $operator: tt) => {
+        {
+            let b = $vm.peek(0);
+            let a = $vm.peek(1);
+
+            let result = match (a, b) {
+                (Value::Int(a), Value::Int(b)) => a $operator b,
+                _ => match (a.as_float(), b.as_float()) {
+                    (Some(a), Some(b)) => a $operator b,
+                    _ => break InterpretResult::RuntimeErr($vm.runtime_error("Operands must be numbers.")),
+                },
+            };
+
+            $vm.pop();
+            $vm.pop();
+            $vm.push(Value::Bool(result));
         }
     };
 }
 
+// Applies `$operator` to the two integers on top of the stack
+macro_rules! bitwise_op {
+    ($vm: ident, $operator: tt) => {
+        {
+            let (Value::Int(a), Value::Int(b)) = ($vm.peek(1), $vm.peek(0)) else {
+                break InterpretResult::RuntimeErr($vm.runtime_error("Operands must be integers."));
+            };
+
+            $vm.pop();
+            $vm.pop();
+            $vm.push(Value::Int(a $operator b));
+        }
+    };
+}
+
+// Shifts by less than the 64 bits of an integer. Shifting right keeps the sign.
+macro_rules! shift_op {
+    ($vm: ident, $checked: ident) => {{
+        let (Value::Int(a), Value::Int(b)) = ($vm.peek(1), $vm.peek(0)) else {
+            break InterpretResult::RuntimeErr($vm.runtime_error("Operands must be integers."));
+        };
+
+        match u32::try_from(b).ok().and_then(|b| a.$checked(b)) {
+            Some(result) => {
+                $vm.pop();
+                $vm.pop();
+                $vm.push(Value::Int(result));
+            }
+            None => {
+                break InterpretResult::RuntimeErr(
+                    $vm.runtime_error("Shift amount must be between 0 and 63."),
+                )
+            }
+        }
+    }};
+}
+
 #[inline]
 fn is_falsey(value: Value) -> bool {
     match value {
@@ -53,47 +207,114 @@ fn is_falsey(value: Value) -> bool {
 impl VM {
     pub fn new() -> Self {
         let mut vm = VM {
-            chunk: Chunk::new(),
-            ip: ptr::null_mut(),
-            stack: [Value::Nil; STACK_MAX],
+            frames: Vec::with_capacity(FRAMES_MAX),
+            stack: vec![Value::Nil; STACK_MAX].into_boxed_slice(),
             stack_top: ptr::null_mut(),
-            all_strings: Vec::new(),
+            globals: Table::new(),
+            strings: Table::new(),
+            init_string: ptr::null_mut(),
+            open_upvalues: ptr::null_mut(),
+            compiler_roots: Vec::new(),
+            heap: Heap::new(),
+            output: Box::new(io::stdout()),
         };
         vm.reset_stack();
+        vm.init_string = unsafe { vm.copy_string("init") };
+
+        vm.define_native("clock", 0, native::clock);
+        vm.define_native("str", 1, native::str);
+        vm.define_native("num", 1, native::num);
+        vm.define_native("len", 1, native::len);
+        vm.define_native("type", 1, native::type_);
+        vm.define_native("append", 2, native::append);
+        vm.define_native("pop", 1, native::pop);
+        vm.define_native("insert", 3, native::insert);
+        vm.define_native("remove", 2, native::remove);
+        vm.define_native("keys", 1, native::keys);
+        vm.define_native("values", 1, native::values);
         vm
     }
 
     #[inline]
+    pub fn with_output(mut self, output: Box<dyn Write>) -> Self {
+        self.output = output;
+        self
+    }
+
+    // Exposes a Rust function to Lox as a global called `name`
+    pub fn define_native(
+        &mut self,
+        name: &str,
+        arity: usize,
+        function: impl Fn(&mut VM, &[Value]) -> Result<Value, String> + 'static,
+    ) {
+        unsafe {
+            // Both objects live on the stack until they're in the globals table, so the GC sees them
+            let name = self.copy_string(name);
+            self.push(Value::Obj(Obj::StringObj(name)));
+            let native = NativeObj::new(arity, Box::new(function));
+            let native = self.allocate(native, Obj::NativeObj);
+            self.push(Value::Obj(Obj::NativeObj(native)));
+
+            self.globals.set(name, self.peek(0));
+            self.pop();
+            self.pop();
+        }
+    }
+
     fn reset_stack(&mut self) {
         self.stack_top = &mut self.stack[0] as *mut Value;
+        self.frames.clear();
+        self.open_upvalues = ptr::null_mut();
     }
 
-    unsafe fn runtime_error(&mut self, message: &str) {
-        eprintln!("{}", message);
+    pub unsafe fn runtime_error(&mut self, message: &str) -> Box<RuntimeError> {
+        let mut error = RuntimeError {
+            diagnostic: Diagnostic::error(message, Span::default()),
+            trace: Vec::with_capacity(self.frames.len()),
+            source: Arc::from(""),
+        };
+
+        for frame in self.frames.iter().rev() {
+            let function = &*(*frame.closure).function;
+            let instruction = frame.ip.offset_from(&function.chunk[0] as *const u8) - 1;
+            let span = function.chunk.get_span(instruction as usize);
+
+            // The error itself points at the innermost instruction
+            if error.trace.is_empty() {
+                error.diagnostic.span = span;
+                error.source = Arc::clone(&function.chunk.source);
+            }
 
-        let instruction = self.ip.offset_from(&self.chunk[0] as *const u8) - 1;
-        let line = self.chunk.lines[instruction as usize];
-        eprintln!("[line {}] in script", line);
+            error.trace.push(TraceFrame {
+                function: function.name.map(|name| (*name).as_str().to_string()),
+                line: span.line,
+            });
+        }
 
         self.reset_stack();
+        Box::new(error)
     }
 
     pub unsafe fn interpret(&mut self, source: &str) -> InterpretResult {
-        let mut parser = Parser::new(source, &mut self.chunk);
-        if !parser.compile() {
-            self.chunk.clear();
-            return InterpretResult::CompileErr;
-        }
-
-        self.ip = &self.chunk[0] as *const u8;
+        let function = match Parser::new(source, self).compile() {
+            Ok(function) => function,
+            Err(diagnostics) => return InterpretResult::CompileErr(diagnostics),
+        };
 
-        let result = self.run();
+        self.reset_stack();
+        self.push(Value::Obj(Obj::FunctionObj(function)));
+        let closure = self.new_closure(function);
+        self.pop();
+        self.push(Value::Obj(Obj::ClosureObj(closure)));
+        if let Err(error) = self.call(closure, 0) {
+            return InterpretResult::RuntimeErr(error);
+        }
 
-        self.chunk.clear();
-        result
+        self.run()
     }
 
-    unsafe fn push(&mut self, value: Value) {
+    pub unsafe fn push(&mut self, value: Value) {
         *self.stack_top = value;
         self.stack_top = self.stack_top.add(1);
     }
@@ -107,6 +328,335 @@ impl VM {
         *self.stack_top.offset(-1 - (distance as isize))
     }
 
+    unsafe fn call_value(
+        &mut self,
+        callee: Value,
+        arg_count: usize,
+    ) -> Result<(), Box<RuntimeError>> {
+        match callee {
+            Value::Obj(Obj::BoundMethodObj(bound)) => {
+                *self.stack_top.sub(arg_count + 1) = (*bound).receiver;
+                self.call((*bound).method, arg_count)
+            }
+            Value::Obj(Obj::ClassObj(class)) => {
+                let instance = self.new_instance(class);
+                *self.stack_top.sub(arg_count + 1) = Value::Obj(Obj::InstanceObj(instance));
+
+                match (*class).methods.get(self.init_string) {
+                    Some(Value::Obj(Obj::ClosureObj(initializer))) => {
+                        self.call(initializer, arg_count)
+                    }
+                    _ if arg_count != 0 => {
+                        let message = format!("Expected 0 arguments but got {}.", arg_count);
+                        Err(self.runtime_error(&message))
+                    }
+                    _ => Ok(()),
+                }
+            }
+            Value::Obj(Obj::ClosureObj(closure)) => self.call(closure, arg_count),
+            Value::Obj(Obj::NativeObj(native)) => {
+                let native = &*native;
+                if arg_count != native.arity {
+                    let message =
+                        format!("Expected {} arguments but got {}.", native.arity, arg_count);
+                    return Err(self.runtime_error(&message));
+                }
+
+                // The arguments stay on the stack during the call so they remain reachable
+                let args = slice::from_raw_parts(self.stack_top.sub(arg_count), arg_count);
+                match (native.function)(self, args) {
+                    Ok(result) => {
+                        self.stack_top = self.stack_top.sub(arg_count + 1);
+                        self.push(result);
+                        Ok(())
+                    }
+                    Err(message) => Err(self.runtime_error(&message)),
+                }
+            }
+            _ => Err(self.runtime_error("Can only call functions and classes.")),
+        }
+    }
+
+    unsafe fn call(
+        &mut self,
+        closure: *mut ClosureObj,
+        arg_count: usize,
+    ) -> Result<(), Box<RuntimeError>> {
+        let function = &*(*closure).function;
+        let arity = function.arity;
+        if arg_count != arity {
+            let message = format!("Expected {} arguments but got {}.", arity, arg_count);
+            return Err(self.runtime_error(&message));
+        }
+
+        if self.frames.len() == FRAMES_MAX {
+            return Err(self.runtime_error("Stack overflow."));
+        }
+
+        self.frames.push(CallFrame {
+            closure,
+            ip: &function.chunk[0] as *const u8,
+            slots: self.stack_top.sub(arg_count + 1),
+        });
+        Ok(())
+    }
+
+    unsafe fn invoke_from_class(
+        &mut self,
+        class: *mut ClassObj,
+        name: *mut StringObj,
+        arg_count: usize,
+    ) -> Result<(), Box<RuntimeError>> {
+        match (*class).methods.get(name) {
+            Some(Value::Obj(Obj::ClosureObj(method))) => self.call(method, arg_count),
+            _ => {
+                let message = format!("Undefined property '{}'.", (*name).as_str());
+                Err(self.runtime_error(&message))
+            }
+        }
+    }
+
+    unsafe fn invoke(
+        &mut self,
+        name: *mut StringObj,
+        arg_count: usize,
+    ) -> Result<(), Box<RuntimeError>> {
+        let instance = match self.peek(arg_count) {
+            Value::Obj(Obj::InstanceObj(instance)) => instance,
+            _ => {
+                return Err(self.runtime_error("Only instances have methods."));
+            }
+        };
+
+        // A field holding a callable shadows any method with the same name
+        if let Some(value) = (*instance).fields.get(name) {
+            *self.stack_top.sub(arg_count + 1) = value;
+            return self.call_value(value, arg_count);
+        }
+
+        self.invoke_from_class((*instance).class, name, arg_count)
+    }
+
+    unsafe fn bind_method(
+        &mut self,
+        class: *mut ClassObj,
+        name: *mut StringObj,
+    ) -> Result<(), Box<RuntimeError>> {
+        let method = match (*class).methods.get(name) {
+            Some(Value::Obj(Obj::ClosureObj(method))) => method,
+            _ => {
+                let message = format!("Undefined property '{}'.", (*name).as_str());
+                return Err(self.runtime_error(&message));
+            }
+        };
+
+        let bound = self.new_bound_method(self.peek(0), method);
+        self.pop();
+        self.push(Value::Obj(Obj::BoundMethodObj(bound)));
+        Ok(())
+    }
+
+    unsafe fn define_method(&mut self, name: *mut StringObj) {
+        let method = self.peek(0);
+        if let Value::Obj(Obj::ClassObj(class)) = self.peek(1) {
+            self.heap
+                .resize(Obj::ClassObj(class), || (*class).methods.set(name, method));
+        }
+        self.pop();
+    }
+
+    // Runs `change` on an object whose storage can grow, so the collector sees the growth
+    pub unsafe fn resize<R>(&mut self, obj: Obj, change: impl FnOnce() -> R) -> R {
+        self.heap.resize(obj, change)
+    }
+
+    unsafe fn allocate<T>(&mut self, object: T, wrap: fn(*mut T) -> Obj) -> *mut T {
+        if self.heap.should_collect() {
+            self.collect_garbage();
+        }
+
+        let ptr = Box::into_raw(Box::new(object));
+        self.heap.link(wrap(ptr));
+        ptr
+    }
+
+    unsafe fn collect_garbage(&mut self) {
+        let stack = &self.stack[..];
+        let stack_len = self.stack_top.offset_from(&self.stack[0] as *const Value) as usize;
+        let frames = &self.frames;
+        let open_upvalues = self.open_upvalues;
+        let globals = &self.globals;
+        let compiler_roots = &self.compiler_roots;
+        let init_string = self.init_string;
+
+        self.heap.collect(&mut self.strings, |heap| {
+            for &value in stack[..stack_len].iter() {
+                heap.mark_value(value);
+            }
+
+            for frame in frames.iter() {
+                heap.mark_object(Obj::ClosureObj(frame.closure));
+            }
+
+            let mut upvalue = open_upvalues;
+            while !upvalue.is_null() {
+                heap.mark_object(Obj::UpvalueObj(upvalue));
+                upvalue = (*upvalue).next;
+            }
+
+            heap.mark_table(globals);
+
+            for &function in compiler_roots.iter() {
+                heap.mark_object(Obj::FunctionObj(function));
+            }
+
+            if !init_string.is_null() {
+                heap.mark_object(Obj::StringObj(init_string));
+            }
+        });
+    }
+
+    pub fn get_global(&self, name: &str) -> Option<Value> {
+        // A name that was never interned can't be a global
+        let name = self.strings.find_string(name, hash_string(name))?;
+        self.globals.get(name)
+    }
+
+    // `value` must already be reachable, for example by being on the stack
+    pub unsafe fn set_global(&mut self, name: &str, value: Value) {
+        self.push(value);
+        let name = self.copy_string(name);
+        self.globals.set(name, self.peek(0));
+        self.pop();
+    }
+
+    // Calls the value sitting below `arg_count` arguments on the stack and runs it to completion
+    pub unsafe fn call_from_host(&mut self, arg_count: usize) -> InterpretResult {
+        if let Err(error) = self.call_value(self.peek(arg_count), arg_count) {
+            return InterpretResult::RuntimeErr(error);
+        }
+
+        // Natives and classes without an initializer are done without pushing a frame
+        if self.frames.is_empty() {
+            return InterpretResult::Ok(self.pop());
+        }
+        self.run()
+    }
+
+    // Whether `count` more values can be pushed
+    pub fn has_stack_room(&self, count: usize) -> bool {
+        self.stack_len() + count <= STACK_MAX
+    }
+
+    // The number of values on the stack
+    pub fn stack_len(&self) -> usize {
+        unsafe { self.stack_top.offset_from(self.stack.as_ptr()) as usize }
+    }
+
+    // Collects garbage and returns the bytes still allocated, so tests can check the accounting
+    #[cfg(test)]
+    pub fn live_bytes(&mut self) -> usize {
+        unsafe { self.collect_garbage() };
+        self.heap.bytes_allocated()
+    }
+
+    pub unsafe fn copy_string(&mut self, chars: &str) -> *mut StringObj {
+        let hash = hash_string(chars);
+        match self.strings.find_string(chars, hash) {
+            Some(interned) => interned,
+            None => self.allocate_string(chars.into(), hash),
+        }
+    }
+
+    pub unsafe fn take_string(&mut self, chars: String) -> *mut StringObj {
+        let hash = hash_string(&chars);
+        match self.strings.find_string(&chars, hash) {
+            Some(interned) => interned,
+            None => self.allocate_string(chars.into_boxed_str(), hash),
+        }
+    }
+
+    unsafe fn allocate_string(&mut self, chars: Box<str>, hash: u32) -> *mut StringObj {
+        let string = self.allocate(StringObj::new(chars, hash), Obj::StringObj);
+        self.strings.set(string, Value::Nil);
+        string
+    }
+
+    // The new function is kept alive as a compiler root until `pop_compiler_root`
+    pub unsafe fn new_function(&mut self) -> *mut FunctionObj {
+        let function = self.allocate(FunctionObj::new(), Obj::FunctionObj);
+        self.compiler_roots.push(function);
+        function
+    }
+
+    pub fn pop_compiler_root(&mut self) {
+        self.compiler_roots.pop();
+    }
+
+    unsafe fn new_closure(&mut self, function: *mut FunctionObj) -> *mut ClosureObj {
+        self.allocate(ClosureObj::new(function), Obj::ClosureObj)
+    }
+
+    // The items must be reachable by the collector until the list is returned
+    pub unsafe fn new_list(&mut self, items: Vec<Value>) -> *mut ListObj {
+        self.allocate(ListObj::new(items), Obj::ListObj)
+    }
+
+    unsafe fn new_class(&mut self, name: *mut StringObj) -> *mut ClassObj {
+        self.allocate(ClassObj::new(name), Obj::ClassObj)
+    }
+
+    unsafe fn new_instance(&mut self, class: *mut ClassObj) -> *mut InstanceObj {
+        self.allocate(InstanceObj::new(class), Obj::InstanceObj)
+    }
+
+    unsafe fn new_bound_method(
+        &mut self,
+        receiver: Value,
+        method: *mut ClosureObj,
+    ) -> *mut BoundMethodObj {
+        self.allocate(BoundMethodObj::new(receiver, method), Obj::BoundMethodObj)
+    }
+
+    unsafe fn capture_upvalue(&mut self, local: *mut Value) -> *mut UpvalueObj {
+        let mut prev_upvalue = ptr::null_mut::<UpvalueObj>();
+        let mut upvalue = self.open_upvalues;
+        while !upvalue.is_null() && (*upvalue).location > local {
+            prev_upvalue = upvalue;
+            upvalue = (*upvalue).next;
+        }
+
+        if !upvalue.is_null() && (*upvalue).location == local {
+            return upvalue;
+        }
+
+        let created_upvalue_ptr = self.allocate(UpvalueObj::new(local), Obj::UpvalueObj);
+        (*created_upvalue_ptr).next = upvalue;
+
+        if prev_upvalue.is_null() {
+            self.open_upvalues = created_upvalue_ptr;
+        } else {
+            (*prev_upvalue).next = created_upvalue_ptr;
+        }
+
+        created_upvalue_ptr
+    }
+
+    unsafe fn close_upvalues(&mut self, last: *mut Value) {
+        while !self.open_upvalues.is_null() && (*self.open_upvalues).location >= last {
+            let upvalue = &mut *self.open_upvalues;
+            upvalue.closed = *upvalue.location;
+            upvalue.location = &mut upvalue.closed as *mut Value;
+            self.open_upvalues = upvalue.next;
+        }
+    }
+
+    #[inline]
+    unsafe fn frame(&mut self) -> &mut CallFrame {
+        let len = self.frames.len();
+        self.frames.get_unchecked_mut(len - 1)
+    }
+
     unsafe fn run(&mut self) -> InterpretResult {
         loop {
             if cfg!(feature = "debug-trace-execution") {
@@ -118,95 +668,602 @@ impl VM {
                 }
                 println!();
 
+                let frame = self.frame();
+                let chunk = &(*(*frame.closure).function).chunk;
                 disassemble_instruction(
-                    &self.chunk,
-                    self.ip.offset_from(&self.chunk[0] as *const u8) as usize,
+                    chunk,
+                    frame.ip.offset_from(&chunk[0] as *const u8) as usize,
                 );
             }
             let instruction = OpCode::try_from(self.read_byte());
+            // Literals and deep expressions can hold many temporaries, so frames alone don't bound
+            // the stack
+            if !self.has_stack_room(STACK_SLACK) {
+                break InterpretResult::RuntimeErr(self.runtime_error("Stack overflow."));
+            }
             match instruction {
                 Ok(opcode) => match opcode {
                     OpCode::Constant => {
                         let constant = self.read_constant();
                         self.push(constant);
                     }
+                    OpCode::ConstantLong => {
+                        let constant = self.read_constant_long();
+                        self.push(constant);
+                    }
                     OpCode::Nil => self.push(Value::Nil),
                     OpCode::True => self.push(Value::Bool(true)),
                     OpCode::False => self.push(Value::Bool(false)),
-                    OpCode::Equal => {
-                        let b = self.pop();
-                        let a = self.pop();
-                        self.push(Value::Bool(a == b));
+                    OpCode::Pop => {
+                        self.pop();
                     }
-                    OpCode::Greater => binary_op!(self, Value::Bool, >),
-                    OpCode::Less => binary_op!(self, Value::Bool, <),
-                    OpCode::Add => {
-                        let b = self.peek(0);
-                        let a = self.peek(1);
-
-                        match (a, b) {
-                            (Value::Number(a), Value::Number(b)) => {
-                                self.pop();
-                                self.pop();
-                                self.push(Value::Number(a + b));
+                    OpCode::GetLocal => {
+                        let slot = self.read_byte() as usize;
+                        let value = *self.frame().slots.add(slot);
+                        self.push(value);
+                    }
+                    OpCode::SetLocal => {
+                        let slot = self.read_byte() as usize;
+                        *self.frame().slots.add(slot) = self.peek(0);
+                    }
+                    OpCode::GetGlobal | OpCode::GetGlobalLong => {
+                        let name = self.read_string(opcode);
+                        match self.globals.get(name) {
+                            Some(value) => self.push(value),
+                            None => {
+                                let message = format!("Undefined variable '{}'.", (*name).as_str());
+                                break InterpretResult::RuntimeErr(self.runtime_error(&message));
                             }
-                            (Value::Obj(Obj::StringObj(a)), Value::Obj(Obj::StringObj(b))) => {
-                                self.concatenate(a, b)
+                        }
+                    }
+                    OpCode::DefineGlobal | OpCode::DefineGlobalLong => {
+                        let name = self.read_string(opcode);
+                        let value = self.peek(0);
+                        self.globals.set(name, value);
+                        self.pop();
+                    }
+                    OpCode::SetGlobal | OpCode::SetGlobalLong => {
+                        let name = self.read_string(opcode);
+                        let value = self.peek(0);
+                        if self.globals.set(name, value) {
+                            self.globals.delete(name);
+                            let message = format!("Undefined variable '{}'.", (*name).as_str());
+                            break InterpretResult::RuntimeErr(self.runtime_error(&message));
+                        }
+                    }
+                    OpCode::GetUpvalue => {
+                        let slot = self.read_byte() as usize;
+                        let upvalue = (&(*self.frame().closure).upvalues)[slot];
+                        self.push(*(*upvalue).location);
+                    }
+                    OpCode::SetUpvalue => {
+                        let slot = self.read_byte() as usize;
+                        let upvalue = (&(*self.frame().closure).upvalues)[slot];
+                        *(*upvalue).location = self.peek(0);
+                    }
+                    OpCode::GetProperty | OpCode::GetPropertyLong => {
+                        let instance = match self.peek(0) {
+                            Value::Obj(Obj::InstanceObj(instance)) => instance,
+                            _ => {
+                                break InterpretResult::RuntimeErr(
+                                    self.runtime_error("Only instances have properties."),
+                                );
                             }
+                        };
+                        let name = self.read_string(opcode);
+
+                        if let Some(value) = (*instance).fields.get(name) {
+                            self.pop(); // Instance
+                            self.push(value);
+                        } else if let Err(error) = self.bind_method((*instance).class, name) {
+                            break InterpretResult::RuntimeErr(error);
+                        }
+                    }
+                    OpCode::SetProperty | OpCode::SetPropertyLong => {
+                        let instance = match self.peek(1) {
+                            Value::Obj(Obj::InstanceObj(instance)) => instance,
                             _ => {
-                                self.runtime_error("Operands must be two numbers or two strings.");
-                                break InterpretResult::RuntimeErr;
+                                break InterpretResult::RuntimeErr(
+                                    self.runtime_error("Only instances have fields."),
+                                );
                             }
+                        };
+                        let name = self.read_string(opcode);
+
+                        let value = self.peek(0);
+                        self.heap.resize(Obj::InstanceObj(instance), || {
+                            (*instance).fields.set(name, value)
+                        });
+                        let value = self.pop();
+                        self.pop(); // Instance
+                        self.push(value);
+                    }
+                    OpCode::GetSuper | OpCode::GetSuperLong => {
+                        let name = self.read_string(opcode);
+                        let superclass = match self.pop() {
+                            Value::Obj(Obj::ClassObj(superclass)) => superclass,
+                            _ => unreachable!(),
+                        };
+
+                        if let Err(error) = self.bind_method(superclass, name) {
+                            break InterpretResult::RuntimeErr(error);
+                        }
+                    }
+                    OpCode::BuildList => {
+                        let item_count = self.read_byte() as usize;
+                        self.build_list(item_count);
+                    }
+                    OpCode::BuildMap => {
+                        let entry_count = self.read_byte() as usize;
+                        if let Err(error) = self.build_map(entry_count) {
+                            break InterpretResult::RuntimeErr(error);
+                        }
+                    }
+                    OpCode::IndexGet => {
+                        if let Err(error) = self.index_get() {
+                            break InterpretResult::RuntimeErr(error);
+                        }
+                    }
+                    OpCode::IndexSet => {
+                        if let Err(error) = self.index_set() {
+                            break InterpretResult::RuntimeErr(error);
                         }
                     }
-                    OpCode::Subtract => binary_op!(self, Value::Number, -),
-                    OpCode::Multiply => binary_op!(self, Value::Number, *),
-                    OpCode::Divide => binary_op!(self, Value::Number, /),
+                    OpCode::Slice => {
+                        if let Err(error) = self.slice_list() {
+                            break InterpretResult::RuntimeErr(error);
+                        }
+                    }
+                    // Values of different types are never equal, except for an integer and a
+                    // float holding the same number: `1 == 1.0` is true, but `"1" == 1` is false
+                    OpCode::Equal => {
+                        let b = self.pop();
+                        let a = self.pop();
+                        self.push(Value::Bool(a == b));
+                    }
+                    OpCode::Greater => comparison_op!(self, >),
+                    OpCode::Less => comparison_op!(self, <),
+                    OpCode::In => {
+                        if let Err(error) = self.contains() {
+                            break InterpretResult::RuntimeErr(error);
+                        }
+                    }
+                    OpCode::Add => match (self.peek(1), self.peek(0)) {
+                        (Value::Obj(Obj::StringObj(a)), Value::Obj(Obj::StringObj(b))) => {
+                            self.concatenate(a, b)
+                        }
+                        (a, b) if a.as_float().is_some() && b.as_float().is_some() => {
+                            binary_op!(self, +, checked_add)
+                        }
+                        _ => {
+                            break InterpretResult::RuntimeErr(
+                                self.runtime_error("Operands must be two numbers or two strings."),
+                            );
+                        }
+                    },
+                    OpCode::Subtract => binary_op!(self, -, checked_sub),
+                    OpCode::Multiply => binary_op!(self, *, checked_mul),
+                    // Always true division, so two integers give a float
+                    OpCode::Divide => match (self.peek(1).as_float(), self.peek(0).as_float()) {
+                        (Some(a), Some(b)) => {
+                            self.pop();
+                            self.pop();
+                            self.push(Value::Number(a / b));
+                        }
+                        _ => {
+                            break InterpretResult::RuntimeErr(
+                                self.runtime_error("Operands must be numbers."),
+                            );
+                        }
+                    },
+                    // Truncates towards zero, like the remainder it pairs with
+                    OpCode::IntDivide => {
+                        binary_op!(self, checked_div, |a: f64, b: f64| (a / b).trunc())
+                    }
+                    OpCode::Modulo => binary_op!(self, %, checked_rem),
+                    OpCode::Power => {
+                        if let Err(error) = self.power() {
+                            break InterpretResult::RuntimeErr(error);
+                        }
+                    }
+                    OpCode::BitAnd => bitwise_op!(self, &),
+                    OpCode::BitOr => bitwise_op!(self, |),
+                    OpCode::BitXor => bitwise_op!(self, ^),
+                    OpCode::ShiftLeft => shift_op!(self, checked_shl),
+                    OpCode::ShiftRight => shift_op!(self, checked_shr),
                     OpCode::Not => {
                         let value = self.pop();
                         self.push(Value::Bool(is_falsey(value)));
                     }
                     OpCode::Negate => match self.peek(0) {
+                        Value::Int(value) => match value.checked_neg() {
+                            Some(value) => {
+                                self.pop();
+                                self.push(Value::Int(value));
+                            }
+                            None => {
+                                break InterpretResult::RuntimeErr(
+                                    self.runtime_error("Integer overflow."),
+                                );
+                            }
+                        },
                         Value::Number(value) => {
                             self.pop();
                             self.push(Value::Number(-value));
                         }
                         _ => {
-                            self.runtime_error("Operand must be a number.");
-                            break InterpretResult::RuntimeErr;
+                            break InterpretResult::RuntimeErr(
+                                self.runtime_error("Operand must be a number."),
+                            );
                         }
                     },
+                    OpCode::BitNot => match self.peek(0) {
+                        Value::Int(value) => {
+                            self.pop();
+                            self.push(Value::Int(!value));
+                        }
+                        _ => {
+                            break InterpretResult::RuntimeErr(
+                                self.runtime_error("Operand must be an integer."),
+                            );
+                        }
+                    },
+                    OpCode::Stringify => {
+                        let value = self.peek(0);
+                        if !matches!(value, Value::Obj(Obj::StringObj(_))) {
+                            // The value stays on the stack until the string is allocated
+                            let string = self.take_string(value.to_string());
+                            self.pop();
+                            self.push(Value::Obj(Obj::StringObj(string)));
+                        }
+                    }
+                    OpCode::Print => {
+                        let value = self.pop();
+                        if writeln!(self.output, "{}", value).is_err() {
+                            break InterpretResult::RuntimeErr(
+                                self.runtime_error("Failed to write output."),
+                            );
+                        }
+                    }
+                    OpCode::Jump => {
+                        let offset = self.read_short();
+                        let frame = self.frame();
+                        frame.ip = frame.ip.add(offset as usize);
+                    }
+                    OpCode::JumpIfFalse => {
+                        let offset = self.read_short();
+                        if is_falsey(self.peek(0)) {
+                            let frame = self.frame();
+                            frame.ip = frame.ip.add(offset as usize);
+                        }
+                    }
+                    OpCode::Loop => {
+                        let offset = self.read_short();
+                        let frame = self.frame();
+                        frame.ip = frame.ip.sub(offset as usize);
+                    }
+                    OpCode::Call => {
+                        let arg_count = self.read_byte() as usize;
+                        if let Err(error) = self.call_value(self.peek(arg_count), arg_count) {
+                            break InterpretResult::RuntimeErr(error);
+                        }
+                    }
+                    OpCode::Invoke | OpCode::InvokeLong => {
+                        let method = self.read_string(opcode);
+                        let arg_count = self.read_byte() as usize;
+                        if let Err(error) = self.invoke(method, arg_count) {
+                            break InterpretResult::RuntimeErr(error);
+                        }
+                    }
+                    OpCode::SuperInvoke | OpCode::SuperInvokeLong => {
+                        let method = self.read_string(opcode);
+                        let arg_count = self.read_byte() as usize;
+                        let superclass = match self.pop() {
+                            Value::Obj(Obj::ClassObj(superclass)) => superclass,
+                            _ => unreachable!(),
+                        };
+                        if let Err(error) = self.invoke_from_class(superclass, method, arg_count) {
+                            break InterpretResult::RuntimeErr(error);
+                        }
+                    }
+                    OpCode::Closure | OpCode::ClosureLong => {
+                        let function = match self.read_operand(opcode) {
+                            Value::Obj(Obj::FunctionObj(function)) => function,
+                            _ => unreachable!(),
+                        };
+                        let closure = self.new_closure(function);
+                        self.push(Value::Obj(Obj::ClosureObj(closure)));
+
+                        for _ in 0..(*function).upvalue_count {
+                            let is_local = self.read_byte() == 1;
+                            let index = self.read_byte() as usize;
+                            let upvalue = if is_local {
+                                let slots = self.frame().slots;
+                                self.capture_upvalue(slots.add(index))
+                            } else {
+                                (&(*self.frame().closure).upvalues)[index]
+                            };
+                            (*closure).upvalues.push(upvalue);
+                        }
+                    }
+                    OpCode::CloseUpvalue => {
+                        self.close_upvalues(self.stack_top.sub(1));
+                        self.pop();
+                    }
                     OpCode::Return => {
-                        println!("{}", self.pop());
-                        break InterpretResult::Ok;
+                        let result = self.pop();
+                        let slots = self.frame().slots;
+                        self.close_upvalues(slots);
+
+                        // Discard the callee and its arguments along with the frame's locals
+                        let frame = self.frames.pop().unwrap();
+                        self.stack_top = frame.slots;
+                        if self.frames.is_empty() {
+                            break InterpretResult::Ok(result);
+                        }
+
+                        self.push(result);
+                    }
+                    OpCode::Class | OpCode::ClassLong => {
+                        let name = self.read_string(opcode);
+                        let class = self.new_class(name);
+                        self.push(Value::Obj(Obj::ClassObj(class)));
+                    }
+                    OpCode::Inherit => {
+                        let superclass = match self.peek(1) {
+                            Value::Obj(Obj::ClassObj(superclass)) => superclass,
+                            _ => {
+                                break InterpretResult::RuntimeErr(
+                                    self.runtime_error("Superclass must be a class."),
+                                );
+                            }
+                        };
+                        if let Value::Obj(Obj::ClassObj(subclass)) = self.peek(0) {
+                            self.heap.resize(Obj::ClassObj(subclass), || {
+                                (*superclass).methods.add_all(&mut (*subclass).methods)
+                            });
+                        }
+                        self.pop(); // Subclass
+                    }
+                    OpCode::Method | OpCode::MethodLong => {
+                        let name = self.read_string(opcode);
+                        self.define_method(name);
                     }
                 },
-                Err(_) => break InterpretResult::RuntimeErr,
+                Err(_) => break InterpretResult::RuntimeErr(self.runtime_error("Unknown opcode.")),
             }
         }
     }
 
-    unsafe fn concatenate(&mut self, a: StringObj, b: StringObj) {
+    unsafe fn build_list(&mut self, item_count: usize) {
+        // The items stay on the stack until the list is allocated
+        let items = slice::from_raw_parts(self.stack_top.sub(item_count), item_count).to_vec();
+        let list = self.new_list(items);
+
+        self.stack_top = self.stack_top.sub(item_count);
+        self.push(Value::Obj(Obj::ListObj(list)));
+    }
+
+    unsafe fn build_map(&mut self, entry_count: usize) -> Result<(), Box<RuntimeError>> {
+        let entries = slice::from_raw_parts(self.stack_top.sub(entry_count * 2), entry_count * 2);
+        for entry in entries.chunks(2) {
+            self.map_key(entry[0])?;
+        }
+
+        // The entries stay on the stack until the map is allocated
+        let map = self.allocate(MapObj::new(), Obj::MapObj);
+        self.heap.resize(Obj::MapObj(map), || {
+            for entry in entries.chunks(2) {
+                (*map).set(entry[0], entry[1]);
+            }
+        });
+
+        self.stack_top = self.stack_top.sub(entry_count * 2);
+        self.push(Value::Obj(Obj::MapObj(map)));
+        Ok(())
+    }
+
+    unsafe fn map_key(&mut self, key: Value) -> Result<Value, Box<RuntimeError>> {
+        if key.is_hashable() {
+            Ok(key)
+        } else {
+            Err(self.runtime_error("Only nil, booleans, numbers and strings can be map keys."))
+        }
+    }
+
+    // Checks that `target[index]` is an item of a list
+    unsafe fn list_index(
+        &mut self,
+        target: Value,
+        index: Value,
+    ) -> Result<(*mut ListObj, usize), Box<RuntimeError>> {
+        let Value::Obj(Obj::ListObj(list)) = target else {
+            return Err(self.runtime_error("Only lists and maps can be indexed."));
+        };
+        let Value::Int(index) = index else {
+            return Err(self.runtime_error("List index must be an integer."));
+        };
+
+        match (*list).index(index) {
+            Some(index) => Ok((list, index)),
+            None => Err(self.runtime_error("Index out of bounds.")),
+        }
+    }
+
+    unsafe fn index_get(&mut self) -> Result<(), Box<RuntimeError>> {
+        let item = match self.peek(1) {
+            Value::Obj(Obj::MapObj(map)) => {
+                let key = self.map_key(self.peek(0))?;
+                match (*map).table.get(key) {
+                    Some(value) => value,
+                    None => {
+                        let message = format!("Undefined key '{}'.", key);
+                        return Err(self.runtime_error(&message));
+                    }
+                }
+            }
+            target => {
+                let (list, index) = self.list_index(target, self.peek(0))?;
+                (&(*list).items)[index]
+            }
+        };
+
         self.pop();
         self.pop();
+        self.push(item);
+        Ok(())
+    }
 
-        let result = String::from(a.as_str()) + b.as_str();
-        self.push(Value::string(result.as_ptr(), result.len()));
+    unsafe fn index_set(&mut self) -> Result<(), Box<RuntimeError>> {
+        match self.peek(2) {
+            Value::Obj(Obj::MapObj(map)) => {
+                let key = self.map_key(self.peek(1))?;
+                let value = self.peek(0);
+                self.heap
+                    .resize(Obj::MapObj(map), || (*map).set(key, value));
+            }
+            target => {
+                let (list, index) = self.list_index(target, self.peek(1))?;
+                (&mut (*list).items)[index] = self.peek(0);
+            }
+        }
 
-        // Make sure result has an owner
-        self.all_strings.push(result);
+        let value = self.pop();
+        self.pop();
+        self.pop();
+        self.push(value);
+        Ok(())
+    }
+
+    // `item in container` checks the keys of a map, or the items of a list
+    unsafe fn contains(&mut self) -> Result<(), Box<RuntimeError>> {
+        let item = self.peek(1);
+        let found = match self.peek(0) {
+            // A value that can't be a key can't be in the map either
+            Value::Obj(Obj::MapObj(map)) => item.is_hashable() && (*map).table.get(item).is_some(),
+            Value::Obj(Obj::ListObj(list)) => (*list).items.contains(&item),
+            _ => {
+                return Err(self.runtime_error("Right operand of 'in' must be a list or a map."));
+            }
+        };
+
+        self.pop();
+        self.pop();
+        self.push(Value::Bool(found));
+        Ok(())
+    }
+
+    // Copies part of a list. Like in Python, bounds past either end are clamped rather than an error.
+    unsafe fn slice_list(&mut self) -> Result<(), Box<RuntimeError>> {
+        let Value::Obj(Obj::ListObj(list)) = self.peek(2) else {
+            return Err(self.runtime_error("Only lists can be sliced."));
+        };
+
+        let len = (*list).items.len() as i64;
+        let bound = |value: Value, default: i64| match value {
+            Value::Nil => Some(default),
+            Value::Int(index) if index < 0 => Some((index + len).max(0)),
+            Value::Int(index) => Some(index.min(len)),
+            _ => None,
+        };
+        let (Some(start), Some(end)) = (bound(self.peek(1), 0), bound(self.peek(0), len)) else {
+            return Err(self.runtime_error("Slice bounds must be integers."));
+        };
+
+        let items = if start < end {
+            (&(*list).items)[start as usize..end as usize].to_vec()
+        } else {
+            Vec::new()
+        };
+        // The original list stays on the stack until the copy is allocated
+        let slice = self.new_list(items);
+
+        self.pop();
+        self.pop();
+        self.pop();
+        self.push(Value::Obj(Obj::ListObj(slice)));
+        Ok(())
+    }
+
+    // Integers stay integers with an exponent that isn't negative, but `2 ** -1` is 0.5
+    unsafe fn power(&mut self) -> Result<(), Box<RuntimeError>> {
+        let result = match (self.peek(1), self.peek(0)) {
+            (Value::Int(a), Value::Int(b)) if b >= 0 => {
+                match u32::try_from(b).ok().and_then(|b| a.checked_pow(b)) {
+                    Some(result) => Value::Int(result),
+                    None => return Err(self.runtime_error("Integer overflow.")),
+                }
+            }
+            (a, b) => match (a.as_float(), b.as_float()) {
+                (Some(a), Some(b)) => Value::Number(a.powf(b)),
+                _ => return Err(self.runtime_error("Operands must be numbers.")),
+            },
+        };
+
+        self.pop();
+        self.pop();
+        self.push(result);
+        Ok(())
+    }
+
+    unsafe fn concatenate(&mut self, a: *mut StringObj, b: *mut StringObj) {
+        let result = String::from((*a).as_str()) + (*b).as_str();
+        // Both operands stay on the stack until the result is allocated
+        let result = self.take_string(result);
+
+        self.pop();
+        self.pop();
+        self.push(Value::Obj(Obj::StringObj(result)));
     }
 
     #[inline]
     unsafe fn read_constant(&mut self) -> Value {
         let constant = self.read_byte() as usize;
-        self.chunk.constants[constant]
+        let function = &*(*self.frame().closure).function;
+        function.chunk.constants[constant]
+    }
+
+    #[inline]
+    unsafe fn read_constant_long(&mut self) -> Value {
+        let frame = self.frame();
+        let constant = (*frame.ip as usize) << 16
+            | (*frame.ip.add(1) as usize) << 8
+            | *frame.ip.add(2) as usize;
+        frame.ip = frame.ip.add(3);
+
+        let function = &*(*frame.closure).function;
+        function.chunk.constants[constant]
+    }
+
+    // Long variants of an instruction have a three-byte constant index
+    unsafe fn read_operand(&mut self, opcode: OpCode) -> Value {
+        if opcode.is_long() {
+            self.read_constant_long()
+        } else {
+            self.read_constant()
+        }
+    }
+
+    unsafe fn read_string(&mut self, opcode: OpCode) -> *mut StringObj {
+        match self.read_operand(opcode) {
+            Value::Obj(Obj::StringObj(obj)) => obj,
+            _ => unreachable!(),
+        }
     }
 
     #[inline]
     unsafe fn read_byte(&mut self) -> u8 {
-        let byte = *self.ip;
-        self.ip = self.ip.add(1);
+        let frame = self.frame();
+        let byte = *frame.ip;
+        frame.ip = frame.ip.add(1);
         byte
     }
+
+    #[inline]
+    unsafe fn read_short(&mut self) -> u16 {
+        let frame = self.frame();
+        let short = ((*frame.ip as u16) << 8) | *frame.ip.add(1) as u16;
+        frame.ip = frame.ip.add(2);
+        short
+    }
 }
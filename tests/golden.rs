@@ -0,0 +1,193 @@
+// Runs every `.lox` file under `tests/` and checks it against the annotations in its comments,
+// using the same format as the craftinginterpreters test suite:
+//
+//     print 1;                       // expect: 1
+//     print nope;                    // expect runtime error: Undefined variable 'nope'.
+//     var = 1;                       // Error at '=': Expect variable name.
+//     // [line 3] Error at end: Expect '}' after block.
+
+use rlox::Vm;
+use std::cell::RefCell;
+use std::fs;
+use std::io::{self, Write};
+use std::path::{Path, PathBuf};
+use std::rc::Rc;
+
+// A writer that can still be read after it's been handed to the VM
+#[derive(Clone, Default)]
+struct Capture(Rc<RefCell<Vec<u8>>>);
+
+impl Write for Capture {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.0.borrow_mut().extend_from_slice(buf);
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+impl Capture {
+    fn lines(&self) -> Vec<String> {
+        let text = String::from_utf8_lossy(&self.0.borrow()).into_owned();
+        text.lines().map(str::to_string).collect()
+    }
+}
+
+#[derive(Default)]
+struct Expectations {
+    output: Vec<String>,
+    compile_errors: Vec<String>,
+    // The message and the line it should be reported on
+    runtime_error: Option<(String, usize)>,
+}
+
+impl Expectations {
+    fn parse(source: &str) -> Self {
+        let mut expected = Expectations::default();
+
+        for (index, line) in source.lines().enumerate() {
+            let line_number = index + 1;
+
+            if let Some((_, output)) = line.split_once("// expect: ") {
+                expected.output.push(output.to_string());
+            } else if let Some((_, message)) = line.split_once("// expect runtime error: ") {
+                expected.runtime_error = Some((message.to_string(), line_number));
+            } else if let Some((_, rest)) = line.split_once("// [line ") {
+                expected.compile_errors.push(format!("[line {}", rest));
+            } else if let Some((_, rest)) = line.split_once("// [c line ") {
+                expected.compile_errors.push(format!("[line {}", rest));
+            } else if let Some((_, rest)) = line.split_once("// Error") {
+                let error = format!("[line {}] Error{}", line_number, rest);
+                expected.compile_errors.push(error);
+            }
+        }
+
+        expected
+    }
+
+    fn exit_code(&self) -> i32 {
+        if !self.compile_errors.is_empty() {
+            65
+        } else if self.runtime_error.is_some() {
+            70
+        } else {
+            0
+        }
+    }
+}
+
+fn run(path: &Path) -> Result<(), String> {
+    let source = fs::read_to_string(path).map_err(|error| error.to_string())?;
+    let expected = Expectations::parse(&source);
+
+    let output = Capture::default();
+    let errors = Capture::default();
+    let mut vm = Vm::new()
+        .with_output(output.clone())
+        .with_error_output(errors.clone());
+
+    let exit_code = match vm.eval(&source) {
+        Ok(_) => 0,
+        Err(error) => {
+            vm.report(&error, &source).unwrap();
+            error.exit_code()
+        }
+    };
+
+    let mut failures = Vec::new();
+
+    let output = output.lines();
+    if output != expected.output {
+        failures.push(format!(
+            "expected output {:#?}\n  got {:#?}",
+            expected.output, output
+        ));
+    }
+
+    // Source snippets and notes are skipped; only the `[line N]` headers are checked
+    let errors = errors.lines();
+    if let Some((message, line)) = &expected.runtime_error {
+        if errors.first() != Some(message) {
+            failures.push(format!(
+                "expected runtime error '{}'\n  got {:#?}",
+                message, errors
+            ));
+        }
+
+        let trace = format!("[line {}]", line);
+        let first_frame = errors.iter().find(|error| error.starts_with("[line "));
+        if !first_frame.is_some_and(|frame| frame.starts_with(&trace)) {
+            failures.push(format!(
+                "expected stack trace to start at {}\n  got {:#?}",
+                trace, errors
+            ));
+        }
+    } else {
+        let compile_errors: Vec<String> = errors
+            .into_iter()
+            .filter(|error| error.starts_with("[line "))
+            .collect();
+        if compile_errors != expected.compile_errors {
+            failures.push(format!(
+                "expected compile errors {:#?}\n  got {:#?}",
+                expected.compile_errors, compile_errors
+            ));
+        }
+    }
+
+    if exit_code != expected.exit_code() {
+        failures.push(format!(
+            "expected exit code {} but got {}",
+            expected.exit_code(),
+            exit_code
+        ));
+    }
+
+    if failures.is_empty() {
+        Ok(())
+    } else {
+        Err(failures.join("\n"))
+    }
+}
+
+fn collect_scripts(dir: &Path, scripts: &mut Vec<PathBuf>) {
+    for entry in fs::read_dir(dir).unwrap() {
+        let path = entry.unwrap().path();
+        if path.is_dir() {
+            collect_scripts(&path, scripts);
+        } else if path.extension().is_some_and(|extension| extension == "lox") {
+            scripts.push(path);
+        }
+    }
+}
+
+#[test]
+fn golden() {
+    let root = Path::new(env!("CARGO_MANIFEST_DIR")).join("tests");
+    let mut scripts = Vec::new();
+    collect_scripts(&root, &mut scripts);
+    scripts.sort();
+    assert!(
+        !scripts.is_empty(),
+        "no scripts found in {}",
+        root.display()
+    );
+
+    let mut failures = Vec::new();
+    for script in scripts.iter() {
+        if let Err(failure) = run(script) {
+            let name = script.strip_prefix(&root).unwrap();
+            failures.push(format!("{}:\n  {}", name.display(), failure));
+        }
+    }
+
+    assert!(
+        failures.is_empty(),
+        "{} of {} scripts failed\n\n{}",
+        failures.len(),
+        scripts.len(),
+        failures.join("\n\n")
+    );
+}